@@ -2,7 +2,9 @@ use anyhow::Result;
 use vergen_gitcl::{Emitter, GitclBuilder};
 
 fn main() -> Result<()> {
-    let gitcl = GitclBuilder::default().build()?;
+    let gitcl = GitclBuilder::default()
+        .describe(true, false, None)
+        .build()?;
 
     let gitcl_res = Emitter::default()
         .idempotent()
@@ -0,0 +1,46 @@
+use anyhow::Result;
+use bitvec_helpers::{bitslice_reader::BitSliceReader, bitstream_io_writer::BitstreamIoWriter};
+use serde::{Deserialize, Serialize};
+
+use super::edit_config::EditAtcMetadata;
+
+/// Alternative transfer characteristics, signalling the preferred transfer function
+/// over the one from the VUI. Usually ARIB STD-B67 (HLG, 18) on PQ/SDR compatible streams.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct AtcMetadata {
+    pub preferred_transfer_characteristics: u8,
+}
+
+impl AtcMetadata {
+    pub fn parse(data: &[u8]) -> Result<Self> {
+        let mut reader = BitSliceReader::new(data);
+
+        Ok(Self {
+            preferred_transfer_characteristics: reader.get_n(8)?,
+        })
+    }
+
+    pub fn encode(&self) -> Result<Vec<u8>> {
+        let mut writer = BitstreamIoWriter::with_capacity(1);
+
+        writer.write::<8, u8>(self.preferred_transfer_characteristics)?;
+
+        Ok(writer.into_inner())
+    }
+
+    pub fn copy(mut self, src: &EditAtcMetadata) -> Self {
+        if let Some(preferred_transfer_characteristics) = src.preferred_transfer_characteristics {
+            self.preferred_transfer_characteristics = preferred_transfer_characteristics;
+        }
+
+        self
+    }
+
+    /// Metadata to insert when the access unit doesn't have any
+    pub fn from_edit(src: &EditAtcMetadata) -> Option<Self> {
+        src.preferred_transfer_characteristics
+            .map(|preferred_transfer_characteristics| Self {
+                preferred_transfer_characteristics,
+            })
+    }
+}
@@ -0,0 +1,63 @@
+use anyhow::Result;
+use bitvec_helpers::{bitslice_reader::BitSliceReader, bitstream_io_writer::BitstreamIoWriter};
+use serde::{Deserialize, Serialize};
+
+use super::edit_config::EditAveMetadata;
+
+/// Ambient viewing environment the content was graded for
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct AveMetadata {
+    /// In units of 0.0001 lux
+    pub ambient_illuminance: u32,
+
+    /// CIE 1931 chromaticity of the ambient light, in units of 0.00002
+    pub ambient_light_x: u16,
+    pub ambient_light_y: u16,
+}
+
+impl AveMetadata {
+    pub fn parse(data: &[u8]) -> Result<Self> {
+        let mut reader = BitSliceReader::new(data);
+
+        Ok(Self {
+            ambient_illuminance: reader.get_n(32)?,
+            ambient_light_x: reader.get_n(16)?,
+            ambient_light_y: reader.get_n(16)?,
+        })
+    }
+
+    pub fn encode(&self) -> Result<Vec<u8>> {
+        let mut writer = BitstreamIoWriter::with_capacity(8);
+
+        writer.write::<32, u32>(self.ambient_illuminance)?;
+        writer.write::<16, u16>(self.ambient_light_x)?;
+        writer.write::<16, u16>(self.ambient_light_y)?;
+
+        Ok(writer.into_inner())
+    }
+
+    pub fn copy(mut self, src: &EditAveMetadata) -> Self {
+        if let Some(ambient_illuminance) = src.ambient_illuminance {
+            self.ambient_illuminance = ambient_illuminance;
+        }
+
+        if let Some(ambient_light_x) = src.ambient_light_x {
+            self.ambient_light_x = ambient_light_x;
+        }
+
+        if let Some(ambient_light_y) = src.ambient_light_y {
+            self.ambient_light_y = ambient_light_y;
+        }
+
+        self
+    }
+
+    /// Metadata to insert when the access unit doesn't have any, all values are required
+    pub fn from_edit(src: &EditAveMetadata) -> Option<Self> {
+        Some(Self {
+            ambient_illuminance: src.ambient_illuminance?,
+            ambient_light_x: src.ambient_light_x?,
+            ambient_light_y: src.ambient_light_y?,
+        })
+    }
+}
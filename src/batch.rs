@@ -0,0 +1,504 @@
+use std::fs::File;
+use std::io::BufWriter;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::time::Instant;
+
+use anyhow::{Result, anyhow, bail, ensure};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use hevc_hdr_editor::{EditConfig, Processor};
+
+use super::output::{OutputFile, OutputTemplate};
+
+/// Files to edit, from a JSON or CSV manifest
+#[derive(Debug, Clone)]
+pub struct BatchManifest {
+    pub entries: Vec<BatchEntry>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct BatchEntry {
+    pub input: PathBuf,
+    /// Output path, from the output template if not set
+    pub output: Option<PathBuf>,
+
+    /// Config replacing the default one
+    pub config: Option<PathBuf>,
+
+    /// Config values overriding the ones from the config file, JSON manifest only.
+    /// Objects are merged, other values are replaced.
+    pub overrides: Option<Value>,
+}
+
+#[derive(Debug, Clone)]
+pub struct BatchOptions {
+    /// Config used for the entries without one
+    pub config: Option<PathBuf>,
+
+    /// Output of the entries without one
+    pub output: OutputTemplate,
+
+    /// Number of files edited in parallel
+    pub jobs: usize,
+
+    /// Keep editing the remaining files after a failure
+    pub continue_on_error: bool,
+
+    /// Skips the remaining files once set, usually from a signal handler
+    pub interrupted: Option<&'static AtomicBool>,
+
+    /// Passes malformed SEI NALs through instead of failing
+    pub tolerant: bool,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct BatchReport {
+    pub succeeded: usize,
+    pub failed: usize,
+    pub skipped: usize,
+
+    pub entries: Vec<BatchEntryReport>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct BatchEntryReport {
+    pub input: PathBuf,
+    /// Not set when the output path couldn't be determined
+    pub output: Option<PathBuf>,
+    pub status: BatchStatus,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    /// SEI NALs passed through in tolerant mode
+    pub malformed_nals: usize,
+    /// Processing time in seconds
+    pub duration: f64,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum BatchStatus {
+    Succeeded,
+    Failed,
+    /// Not processed, after a failure without `continue_on_error` or an interruption
+    Skipped,
+}
+
+impl BatchManifest {
+    /// CSV manifests are detected by the `.csv` extension, with an `input[,output][,config]` header.
+    /// Relative paths are resolved from the manifest directory.
+    pub fn from_path<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let path = path.as_ref();
+        let contents = std::fs::read_to_string(path)?;
+
+        let is_csv = path
+            .extension()
+            .is_some_and(|ext| ext.eq_ignore_ascii_case("csv"));
+
+        let mut manifest = if is_csv {
+            Self::parse_csv(&contents)?
+        } else {
+            Self {
+                entries: serde_json::from_str(&contents)?,
+            }
+        };
+
+        if let Some(dir) = path.parent() {
+            for entry in manifest.entries.iter_mut() {
+                entry.input = dir.join(&entry.input);
+                entry.output = entry.output.as_ref().map(|output| dir.join(output));
+                entry.config = entry.config.as_ref().map(|config| dir.join(config));
+            }
+        }
+
+        Ok(manifest)
+    }
+
+    fn parse_csv(contents: &str) -> Result<Self> {
+        let mut lines = contents.lines().filter(|line| !line.trim().is_empty());
+
+        let Some(header) = lines.next() else {
+            bail!("Empty CSV manifest");
+        };
+        let header = parse_csv_line(header)?;
+
+        let column = |name: &str| header.iter().position(|col| col.trim() == name);
+        let Some(input_idx) = column("input") else {
+            bail!("CSV manifest header requires an input column");
+        };
+        let output_idx = column("output");
+        let config_idx = column("config");
+
+        let optional_path = |fields: &[String], idx: Option<usize>| {
+            idx.map(|idx| &fields[idx])
+                .filter(|value| !value.is_empty())
+                .map(PathBuf::from)
+        };
+
+        let entries = lines
+            .map(|line| {
+                let fields = parse_csv_line(line)?;
+                ensure!(
+                    fields.len() == header.len(),
+                    "CSV manifest line has {} fields, expected {}: {line}",
+                    fields.len(),
+                    header.len()
+                );
+
+                Ok(BatchEntry {
+                    input: PathBuf::from(&fields[input_idx]),
+                    output: optional_path(&fields, output_idx),
+                    config: optional_path(&fields, config_idx),
+                    overrides: None,
+                })
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        Ok(Self { entries })
+    }
+
+    /// Edits every entry, the errors are part of the report
+    pub fn run(&self, options: &BatchOptions) -> BatchReport {
+        let conflicts = self.output_conflicts(options);
+
+        let next_entry = AtomicUsize::new(0);
+        let stop = AtomicBool::new(false);
+        let reports = Mutex::new(vec![None; self.entries.len()]);
+
+        let worker = || {
+            loop {
+                let idx = next_entry.fetch_add(1, Ordering::Relaxed);
+                let Some(entry) = self.entries.get(idx) else {
+                    break;
+                };
+
+                let output = entry.output_path(options).ok();
+
+                let interrupted = options
+                    .interrupted
+                    .is_some_and(|flag| flag.load(Ordering::Relaxed));
+
+                let report = if interrupted || stop.load(Ordering::Relaxed) {
+                    entry.report(output, BatchStatus::Skipped, None, 0.0)
+                } else {
+                    let start = Instant::now();
+                    let res = match conflicts[idx].as_ref() {
+                        Some(conflict) => Err(anyhow!("{conflict}")),
+                        None => entry.process(options),
+                    };
+                    let duration = start.elapsed().as_secs_f64();
+
+                    match res {
+                        Ok(malformed_nals) => BatchEntryReport {
+                            malformed_nals,
+                            ..entry.report(output, BatchStatus::Succeeded, None, duration)
+                        },
+                        Err(e) => {
+                            if !options.continue_on_error {
+                                stop.store(true, Ordering::Relaxed);
+                            }
+
+                            let error = Some(format!("{e:#}"));
+                            entry.report(output, BatchStatus::Failed, error, duration)
+                        }
+                    }
+                };
+
+                reports.lock().unwrap()[idx] = Some(report);
+            }
+        };
+
+        std::thread::scope(|scope| {
+            for _ in 0..options.jobs.clamp(1, self.entries.len().max(1)) {
+                scope.spawn(worker);
+            }
+        });
+
+        let entries: Vec<_> = reports
+            .into_inner()
+            .unwrap()
+            .into_iter()
+            .flatten()
+            .collect();
+        let count = |status| entries.iter().filter(|e| e.status == status).count();
+
+        BatchReport {
+            succeeded: count(BatchStatus::Succeeded),
+            failed: count(BatchStatus::Failed),
+            skipped: count(BatchStatus::Skipped),
+            entries,
+        }
+    }
+
+    /// Error of each entry writing to the output or the input of another entry,
+    /// resolved before editing so that no entry overwrites another
+    fn output_conflicts(&self, options: &BatchOptions) -> Vec<Option<String>> {
+        let outputs: Vec<_> = self
+            .entries
+            .iter()
+            .map(|entry| entry.output_path(options).ok())
+            .collect();
+        let output_keys: Vec<_> = outputs
+            .iter()
+            .map(|output| output.as_deref().map(comparable_path))
+            .collect();
+        let input_keys: Vec<_> = self
+            .entries
+            .iter()
+            .map(|entry| comparable_path(&entry.input))
+            .collect();
+
+        output_keys
+            .iter()
+            .enumerate()
+            .map(|(idx, key)| {
+                let key = key.as_ref()?;
+                let output = outputs[idx].as_ref()?;
+                let others = || (0..self.entries.len()).filter(move |&other| other != idx);
+
+                if let Some(other) =
+                    others().find(|&other| output_keys[other].as_ref() == Some(key))
+                {
+                    return Some(format!(
+                        "Output {} is also the output of input {}",
+                        output.display(),
+                        self.entries[other].input.display()
+                    ));
+                }
+
+                others()
+                    .find(|&other| input_keys[other] == *key)
+                    .map(|other| {
+                        format!(
+                            "Output {} is the input of another entry",
+                            self.entries[other].input.display()
+                        )
+                    })
+            })
+            .collect()
+    }
+}
+
+impl BatchEntry {
+    fn output_path(&self, options: &BatchOptions) -> Result<PathBuf> {
+        match self.output.as_ref() {
+            Some(output) => Ok(output.clone()),
+            None => options.output.output_path(&self.input),
+        }
+    }
+
+    /// Returns the number of malformed SEI NALs passed through
+    fn process(&self, options: &BatchOptions) -> Result<usize> {
+        let output = self.output_path(options)?;
+        let config = self.edit_config(options)?;
+        let writer = BufWriter::with_capacity(100_000, OutputFile::create(&self.input, &output)?);
+
+        let mut processor = Processor::new(config, writer)?.with_tolerant(options.tolerant);
+        if let Some(interrupted) = options.interrupted {
+            processor = processor.with_interrupt_flag(interrupted);
+        }
+
+        processor.edit_file(&self.input)?;
+        let malformed_nals = processor.malformed_nals().len();
+
+        processor
+            .into_writer()
+            .into_inner()
+            .map_err(|e| e.into_error())?
+            .persist()?;
+
+        Ok(malformed_nals)
+    }
+
+    /// Config file of the entry or the default one, with the overrides merged
+    fn edit_config(&self, options: &BatchOptions) -> Result<EditConfig> {
+        let Some(path) = self.config.as_ref().or(options.config.as_ref()) else {
+            bail!("No config for input {}", self.input.display());
+        };
+
+        let mut config: Value = serde_json::from_reader(File::open(path)?)?;
+        if let Some(overrides) = self.overrides.as_ref() {
+            merge_json(&mut config, overrides);
+        }
+
+        Ok(serde_json::from_value(config)?)
+    }
+
+    fn report(
+        &self,
+        output: Option<PathBuf>,
+        status: BatchStatus,
+        error: Option<String>,
+        duration: f64,
+    ) -> BatchEntryReport {
+        BatchEntryReport {
+            input: self.input.clone(),
+            output,
+            status,
+            error,
+            malformed_nals: 0,
+            duration,
+        }
+    }
+}
+
+impl BatchReport {
+    pub const fn is_success(&self) -> bool {
+        self.failed == 0 && self.skipped == 0
+    }
+}
+
+fn merge_json(target: &mut Value, overrides: &Value) {
+    match (target, overrides) {
+        (Value::Object(target), Value::Object(overrides)) => {
+            for (key, value) in overrides {
+                match target.get_mut(key) {
+                    Some(existing) => merge_json(existing, value),
+                    None => {
+                        target.insert(key.clone(), value.clone());
+                    }
+                }
+            }
+        }
+        (target, overrides) => *target = overrides.clone(),
+    }
+}
+
+/// Absolute path for comparisons, also for the outputs that don't exist yet
+fn comparable_path(path: &Path) -> PathBuf {
+    if let Ok(path) = path.canonicalize() {
+        return path;
+    }
+
+    let parent = path
+        .parent()
+        .filter(|parent| !parent.as_os_str().is_empty())
+        .unwrap_or(Path::new("."));
+
+    match (parent.canonicalize(), path.file_name()) {
+        (Ok(parent), Some(name)) => parent.join(name),
+        _ => std::path::absolute(path).unwrap_or_else(|_| path.to_path_buf()),
+    }
+}
+
+/// Comma separated fields, optionally quoted with `"` and `""` escapes
+fn parse_csv_line(line: &str) -> Result<Vec<String>> {
+    let mut fields = Vec::new();
+    let mut field = String::new();
+    let mut in_quotes = false;
+    let mut chars = line.trim_end_matches('\r').chars().peekable();
+
+    while let Some(c) = chars.next() {
+        match c {
+            '"' if in_quotes && chars.peek() == Some(&'"') => {
+                field.push('"');
+                chars.next();
+            }
+            '"' => in_quotes = !in_quotes,
+            ',' if !in_quotes => fields.push(std::mem::take(&mut field)),
+            c => field.push(c),
+        }
+    }
+
+    ensure!(!in_quotes, "Unterminated quoted field in CSV line: {line}");
+    fields.push(field);
+
+    Ok(fields)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_csv() -> Result<()> {
+        let manifest = BatchManifest::parse_csv(
+            "input,output,config\n\
+             a.hevc,a_out.hevc,\n\
+             \"b, \"\"quoted\"\".hevc\",b_out.hevc,b.json\n",
+        )?;
+
+        assert_eq!(manifest.entries.len(), 2);
+        assert_eq!(manifest.entries[0].config, None);
+        assert_eq!(
+            manifest.entries[1].input,
+            PathBuf::from("b, \"quoted\".hevc")
+        );
+        assert_eq!(manifest.entries[1].config, Some(PathBuf::from("b.json")));
+
+        assert!(BatchManifest::parse_csv("output,config\na.hevc,a.json").is_err());
+        assert!(BatchManifest::parse_csv("input,output\na.hevc").is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_output_conflicts() {
+        let entry = |input: &str, output: Option<&str>| BatchEntry {
+            input: PathBuf::from(input),
+            output: output.map(PathBuf::from),
+            config: None,
+            overrides: None,
+        };
+        let manifest = BatchManifest {
+            entries: vec![
+                entry("season1/S01E01.hevc", None),
+                entry("season2/S01E01.mkv", None),
+                entry("season1/S01E02.hevc", Some("out/S01E02.hevc")),
+                entry("season1/S01E03.hevc", Some("season1/./S01E02.hevc")),
+            ],
+        };
+        let options = BatchOptions {
+            config: None,
+            output: OutputTemplate {
+                dir: Some(PathBuf::from("out")),
+                template: None,
+            },
+            jobs: 1,
+            continue_on_error: false,
+            interrupted: None,
+            tolerant: false,
+        };
+
+        let conflicts = manifest.output_conflicts(&options);
+        assert_eq!(
+            conflicts[0].as_deref(),
+            Some(
+                "Output out/S01E01_hdr_edited.hevc is also the output of input season2/S01E01.mkv"
+            )
+        );
+        assert!(conflicts[1].is_some());
+        assert_eq!(conflicts[2], None);
+        assert_eq!(
+            conflicts[3].as_deref(),
+            Some("Output season1/S01E02.hevc is the input of another entry")
+        );
+    }
+
+    #[test]
+    fn test_merge_json() {
+        let mut config = serde_json::json!({
+            "cll": { "max_content_light_level": 1000, "max_frame_average_light_level": 400 },
+            "atc": { "remove": true }
+        });
+        merge_json(
+            &mut config,
+            &serde_json::json!({
+                "cll": { "max_content_light_level": 2000 },
+                "vui": { "colour_primaries": 9 }
+            }),
+        );
+
+        assert_eq!(
+            config,
+            serde_json::json!({
+                "cll": { "max_content_light_level": 2000, "max_frame_average_light_level": 400 },
+                "atc": { "remove": true },
+                "vui": { "colour_primaries": 9 }
+            })
+        );
+    }
+}
@@ -0,0 +1,237 @@
+use anyhow::Result;
+use bitvec_helpers::{bitslice_reader::BitSliceReader, bitstream_io_writer::BitstreamIoWriter};
+use serde::{Deserialize, Serialize};
+
+use super::edit_config::EditCcvMetadata;
+use super::mdcv_metadata::{COMPONENTS_MAPPING, MasteringDisplayPrimaries};
+
+/// Luminance values are in units of 0.0000001 nits
+pub const CCV_LUMINANCE_FACTOR: f64 = 10_000_000.0;
+
+/// Colour volume of the content itself, as opposed to the mastering display
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct ContentColourVolume {
+    /// Cancels the persistence of the previous message, no values are present
+    pub cancel_flag: bool,
+    pub persistence_flag: bool,
+
+    pub primaries: Option<ContentColourVolumePrimaries>,
+
+    /// In units of 0.0000001 nits
+    pub min_luminance_value: Option<u32>,
+    pub max_luminance_value: Option<u32>,
+    pub avg_luminance_value: Option<u32>,
+}
+
+/// Values in units of 0.00002, in r,g,b order
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct ContentColourVolumePrimaries {
+    pub primaries_x: [i32; 3],
+    pub primaries_y: [i32; 3],
+}
+
+impl ContentColourVolume {
+    pub fn parse(data: &[u8]) -> Result<Self> {
+        let mut reader = BitSliceReader::new(data);
+
+        let cancel_flag = reader.get()?;
+        if cancel_flag {
+            return Ok(Self::cancel());
+        }
+
+        let persistence_flag = reader.get()?;
+        let primaries_present_flag = reader.get()?;
+        let min_luminance_value_present_flag = reader.get()?;
+        let max_luminance_value_present_flag = reader.get()?;
+        let avg_luminance_value_present_flag = reader.get()?;
+
+        // ccv_reserved_zero_2bits
+        reader.skip_n(2)?;
+
+        let primaries = if primaries_present_flag {
+            let mut primaries_x = [0; 3];
+            let mut primaries_y = [0; 3];
+
+            // reorder directly into r,g,b ordering
+            for c in COMPONENTS_MAPPING {
+                primaries_x[c] = reader.get_n::<u32>(32)? as i32;
+                primaries_y[c] = reader.get_n::<u32>(32)? as i32;
+            }
+
+            Some(ContentColourVolumePrimaries {
+                primaries_x,
+                primaries_y,
+            })
+        } else {
+            None
+        };
+
+        let mut get_if_present = |present_flag: bool| -> Result<Option<u32>> {
+            Ok(if present_flag {
+                Some(reader.get_n(32)?)
+            } else {
+                None
+            })
+        };
+
+        Ok(Self {
+            cancel_flag,
+            persistence_flag,
+            primaries,
+            min_luminance_value: get_if_present(min_luminance_value_present_flag)?,
+            max_luminance_value: get_if_present(max_luminance_value_present_flag)?,
+            avg_luminance_value: get_if_present(avg_luminance_value_present_flag)?,
+        })
+    }
+
+    pub fn encode(&self) -> Result<Vec<u8>> {
+        let mut writer = BitstreamIoWriter::with_capacity(37);
+
+        writer.write_bit(self.cancel_flag)?;
+
+        if self.cancel_flag {
+            // payload alignment: bit_equal_to_one, then zero bits
+            writer.write_bit(true)?;
+            writer.byte_align()?;
+
+            return Ok(writer.into_inner());
+        }
+
+        writer.write_bit(self.persistence_flag)?;
+        writer.write_bit(self.primaries.is_some())?;
+        writer.write_bit(self.min_luminance_value.is_some())?;
+        writer.write_bit(self.max_luminance_value.is_some())?;
+        writer.write_bit(self.avg_luminance_value.is_some())?;
+        writer.write::<2, u8>(0)?;
+
+        if let Some(primaries) = &self.primaries {
+            // reorder back to gbr
+            for c in COMPONENTS_MAPPING {
+                writer.write::<32, u32>(primaries.primaries_x[c] as u32)?;
+                writer.write::<32, u32>(primaries.primaries_y[c] as u32)?;
+            }
+        }
+
+        let luminance_values = [
+            self.min_luminance_value,
+            self.max_luminance_value,
+            self.avg_luminance_value,
+        ];
+        for value in luminance_values.into_iter().flatten() {
+            writer.write::<32, u32>(value)?;
+        }
+
+        Ok(writer.into_inner())
+    }
+
+    pub fn copy(mut self, src: &EditCcvMetadata) -> Self {
+        let primaries = src.primaries.clone().or_else(|| {
+            src.preset
+                .as_ref()
+                .map(|preset| ContentColourVolumePrimaries::from(preset.primaries()))
+        });
+        let luminance_values = [
+            (src.min_luminance_value, &mut self.min_luminance_value),
+            (src.max_luminance_value, &mut self.max_luminance_value),
+            (src.avg_luminance_value, &mut self.avg_luminance_value),
+        ];
+
+        let mut edited = primaries.is_some();
+        for (nits, value) in luminance_values {
+            if let Some(nits) = nits {
+                *value = Some(nits_to_ccv_luminance(nits));
+                edited = true;
+            }
+        }
+
+        if primaries.is_some() {
+            self.primaries = primaries;
+        }
+
+        // Values can't be present in a cancelling message
+        if edited && self.cancel_flag {
+            self.cancel_flag = false;
+            self.persistence_flag = true;
+        }
+
+        self
+    }
+
+    /// Metadata to insert when the access unit doesn't have any
+    pub fn from_edit(src: &EditCcvMetadata) -> Self {
+        Self {
+            cancel_flag: false,
+            persistence_flag: true,
+            primaries: None,
+            min_luminance_value: None,
+            max_luminance_value: None,
+            avg_luminance_value: None,
+        }
+        .copy(src)
+    }
+
+    const fn cancel() -> Self {
+        Self {
+            cancel_flag: true,
+            persistence_flag: false,
+            primaries: None,
+            min_luminance_value: None,
+            max_luminance_value: None,
+            avg_luminance_value: None,
+        }
+    }
+}
+
+impl From<MasteringDisplayPrimaries> for ContentColourVolumePrimaries {
+    fn from(primaries: MasteringDisplayPrimaries) -> Self {
+        Self {
+            primaries_x: primaries.display_primaries_x.map(i32::from),
+            primaries_y: primaries.display_primaries_y.map(i32::from),
+        }
+    }
+}
+
+pub fn nits_to_ccv_luminance(nits: f64) -> u32 {
+    (nits * CCV_LUMINANCE_FACTOR).round() as u32
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_encode_roundtrip() -> Result<()> {
+        // BT.2020 primaries, min 0.005, max 400, avg 100 nits
+        let data: &[u8] = &[
+            0x7C, 0x00, 0x00, 0x21, 0x34, 0x00, 0x00, 0x9B, 0xAA, 0x00, 0x00, 0x19, 0x96, 0x00,
+            0x00, 0x08, 0xFC, 0x00, 0x00, 0x8A, 0x48, 0x00, 0x00, 0x39, 0x08, 0x00, 0x00, 0xC3,
+            0x50, 0xEE, 0x6B, 0x28, 0x00, 0x3B, 0x9A, 0xCA, 0x00,
+        ];
+
+        let res = ContentColourVolume::parse(data)?;
+        assert_eq!(
+            res,
+            ContentColourVolume {
+                cancel_flag: false,
+                persistence_flag: true,
+                primaries: Some(MasteringDisplayPrimaries::bt2020().into()),
+                min_luminance_value: Some(50_000),
+                max_luminance_value: Some(4_000_000_000),
+                avg_luminance_value: Some(1_000_000_000),
+            }
+        );
+
+        // round trips back to original
+        let encoded = res.encode()?;
+        assert_eq!(data, &encoded);
+
+        // cancel message
+        let data: &[u8] = &[0xC0];
+
+        let res = ContentColourVolume::parse(data)?;
+        assert!(res.cancel_flag);
+        assert_eq!(data, &res.encode()?);
+
+        Ok(())
+    }
+}
@@ -2,9 +2,10 @@ use anyhow::Result;
 use bitvec_helpers::{bitslice_reader::BitSliceReader, bitstream_io_writer::BitstreamIoWriter};
 use serde::{Deserialize, Serialize};
 
-use super::edit_config::EditCllMetadata;
+use super::edit_config::{CllMatchCondition, CllTransform, EditCllMetadata};
+use super::mdcv_metadata::MdcvMetadata;
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
 pub struct CllMetadata {
     pub max_content_light_level: u16,
     pub max_frame_average_light_level: u16,
@@ -40,4 +41,57 @@ impl CllMetadata {
 
         self
     }
+
+    /// The MDCV metadata is required to clamp the values, otherwise the transform is ignored
+    pub fn transform(mut self, transforms: &[CllTransform], mdcv: Option<&MdcvMetadata>) -> Self {
+        for transform in transforms {
+            match transform {
+                CllTransform::ClampToMdcv => {
+                    if let Some(mdcv) = mdcv {
+                        let max_luminance = mdcv.max_luminance().round() as u16;
+
+                        self.max_content_light_level =
+                            self.max_content_light_level.min(max_luminance);
+                        self.max_frame_average_light_level =
+                            self.max_frame_average_light_level.min(max_luminance);
+                    }
+                }
+                CllTransform::Scale(factor) => {
+                    self.max_content_light_level =
+                        scale_light_level(self.max_content_light_level, *factor);
+                    self.max_frame_average_light_level =
+                        scale_light_level(self.max_frame_average_light_level, *factor);
+                }
+                CllTransform::LimitMaxFall => {
+                    self.max_frame_average_light_level = self
+                        .max_frame_average_light_level
+                        .min(self.max_content_light_level);
+                }
+            }
+        }
+
+        self
+    }
+
+    /// Whether the metadata should be edited, according to the edit's match conditions
+    pub fn should_edit(&self, src: &EditCllMetadata) -> bool {
+        src.match_condition
+            .as_ref()
+            .is_none_or(|condition| self.matches(condition))
+    }
+
+    pub fn matches(&self, condition: &CllMatchCondition) -> bool {
+        let max_cll_matches = condition
+            .max_content_light_level
+            .is_none_or(|max_cll| self.max_content_light_level == max_cll);
+        let max_fall_matches = condition
+            .max_frame_average_light_level
+            .is_none_or(|max_fall| self.max_frame_average_light_level == max_fall);
+
+        max_cll_matches && max_fall_matches
+    }
+}
+
+fn scale_light_level(value: u16, factor: f32) -> u16 {
+    (value as f32 * factor).round() as u16
 }
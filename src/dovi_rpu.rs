@@ -0,0 +1,667 @@
+use anyhow::{Result, bail, ensure};
+use bitvec_helpers::bitslice_reader::BitSliceReader;
+use serde::{Deserialize, Serialize};
+
+use super::cll_metadata::CllMetadata;
+use super::mdcv_metadata::MdcvMetadata;
+
+use hevc_parser::utils::{
+    add_start_code_emulation_prevention_3_byte, clear_start_code_emulation_prevention_3_byte,
+};
+
+const NAL_HEADER: [u8; 2] = [0x7C, 0x01];
+const RPU_PREFIX: u8 = 0x19;
+const FINAL_BYTE: u8 = 0x80;
+
+const NUM_COMPONENTS: usize = 3;
+
+const MAPPING_POLYNOMIAL: u64 = 0;
+const MAPPING_MMR: u64 = 1;
+
+const NLQ_LINEAR_DZ: u8 = 0;
+
+/// Dolby Vision RPU, only parsed as far as required to access the DM extension metadata
+#[derive(Debug, Clone)]
+pub struct DoviRpu {
+    /// Unescaped payload, starting with the RPU prefix and ending with the final byte
+    data: Vec<u8>,
+
+    pub ext_blocks: Vec<ExtMetadataBlock>,
+}
+
+#[derive(Debug, Clone)]
+pub struct ExtMetadataBlock {
+    pub level: u8,
+
+    /// Offset of the block payload in the RPU data, in bits
+    offset: usize,
+}
+
+/// Per-shot content light levels, as 12 bit PQ codes
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ExtMetadataBlockLevel1 {
+    pub min_pq: u16,
+    pub max_pq: u16,
+    pub avg_pq: u16,
+}
+
+/// Static HDR10 metadata
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ExtMetadataBlockLevel6 {
+    /// In nits
+    pub max_display_mastering_luminance: u16,
+    /// In units of 0.0001 nits
+    pub min_display_mastering_luminance: u16,
+
+    pub max_content_light_level: u16,
+    pub max_frame_average_light_level: u16,
+}
+
+#[derive(Debug, Clone)]
+struct RpuDataHeader {
+    coefficient_data_type: u8,
+    coefficient_log2_denom: u64,
+
+    el_bit_depth: usize,
+
+    vdr_dm_metadata_present_flag: bool,
+    use_prev_vdr_rpu_flag: bool,
+
+    num_pivots: [usize; NUM_COMPONENTS],
+    nlq_method_idc: Option<u8>,
+}
+
+impl DoviRpu {
+    /// Parses the RPU from an UNSPEC62 NAL, without start code
+    pub fn parse_unspec62_nalu(nal_data: &[u8]) -> Result<Self> {
+        ensure!(
+            nal_data.len() > NAL_HEADER.len() && nal_data[..2] == NAL_HEADER,
+            "Invalid Dolby Vision RPU NAL header"
+        );
+
+        let mut data = clear_start_code_emulation_prevention_3_byte(&nal_data[2..]);
+
+        // cabac_zero_words
+        while data.last() == Some(&0) {
+            data.pop();
+        }
+
+        ensure!(
+            data.len() > 6 && data[0] == RPU_PREFIX && data.last() == Some(&FINAL_BYTE),
+            "Invalid Dolby Vision RPU data"
+        );
+
+        let crc_offset = data.len() - 5;
+        let received_crc32 = u32::from_be_bytes(data[crc_offset..crc_offset + 4].try_into()?);
+        let computed_crc32 = crc32(&data[1..crc_offset]);
+
+        ensure!(
+            received_crc32 == computed_crc32,
+            "RPU CRC32 mismatch: {computed_crc32:08X} vs expected {received_crc32:08X}"
+        );
+
+        let mut reader = BitSliceReader::new(&data[1..crc_offset]);
+        let header = RpuDataHeader::parse(&mut reader)?;
+
+        if !header.use_prev_vdr_rpu_flag {
+            header.skip_mapping(&mut reader)?;
+        }
+
+        let mut ext_blocks = Vec::new();
+
+        if header.vdr_dm_metadata_present_flag {
+            skip_vdr_dm_data_payload(&mut reader)?;
+            parse_ext_blocks(&mut reader, &mut ext_blocks)?;
+
+            // CM v4.0 extension blocks
+            if reader.available() >= 8 {
+                parse_ext_blocks(&mut reader, &mut ext_blocks)?;
+            }
+        }
+
+        Ok(Self { data, ext_blocks })
+    }
+
+    pub fn level1(&self) -> Result<Option<ExtMetadataBlockLevel1>> {
+        let Some(block) = self.ext_blocks.iter().find(|b| b.level == 1) else {
+            return Ok(None);
+        };
+
+        let mut reader = BitSliceReader::new(&self.data);
+        reader.skip_n(block.offset)?;
+
+        Ok(Some(ExtMetadataBlockLevel1 {
+            min_pq: reader.get_n(12)?,
+            max_pq: reader.get_n(12)?,
+            avg_pq: reader.get_n(12)?,
+        }))
+    }
+
+    pub fn level6(&self) -> Result<Option<ExtMetadataBlockLevel6>> {
+        let Some(block) = self.ext_blocks.iter().find(|b| b.level == 6) else {
+            return Ok(None);
+        };
+
+        let mut reader = BitSliceReader::new(&self.data);
+        reader.skip_n(block.offset)?;
+
+        Ok(Some(ExtMetadataBlockLevel6 {
+            max_display_mastering_luminance: reader.get_n(16)?,
+            min_display_mastering_luminance: reader.get_n(16)?,
+            max_content_light_level: reader.get_n(16)?,
+            max_frame_average_light_level: reader.get_n(16)?,
+        }))
+    }
+
+    /// Overwrites the existing L6 metadata in place.
+    /// Returns `false` if the RPU has no L6 metadata.
+    pub fn set_level6(&mut self, level6: &ExtMetadataBlockLevel6) -> bool {
+        let Some(offset) = self
+            .ext_blocks
+            .iter()
+            .find(|b| b.level == 6)
+            .map(|b| b.offset)
+        else {
+            return false;
+        };
+
+        let values = [
+            level6.max_display_mastering_luminance,
+            level6.min_display_mastering_luminance,
+            level6.max_content_light_level,
+            level6.max_frame_average_light_level,
+        ];
+
+        for (i, value) in values.into_iter().enumerate() {
+            write_bits(&mut self.data, offset + i * 16, 16, value as u32);
+        }
+
+        let crc_offset = self.data.len() - 5;
+        let crc = crc32(&self.data[1..crc_offset]);
+        self.data[crc_offset..crc_offset + 4].copy_from_slice(&crc.to_be_bytes());
+
+        true
+    }
+
+    /// Encodes the RPU back to an UNSPEC62 NAL, without start code
+    pub fn write_unspec62_nalu(&self) -> Vec<u8> {
+        let mut data = self.data.clone();
+        add_start_code_emulation_prevention_3_byte(&mut data);
+
+        let mut nal = Vec::with_capacity(NAL_HEADER.len() + data.len());
+        nal.extend_from_slice(&NAL_HEADER);
+        nal.extend_from_slice(&data);
+
+        nal
+    }
+}
+
+impl ExtMetadataBlockLevel6 {
+    /// Replaces the values with the HDR10 metadata, when present
+    pub fn copy_hdr10(&mut self, mdcv: Option<&MdcvMetadata>, cll: Option<&CllMetadata>) {
+        if let Some(mdcv) = mdcv {
+            self.max_display_mastering_luminance = mdcv.max_luminance().round() as u16;
+            self.min_display_mastering_luminance =
+                mdcv.min_display_mastering_luminance.min(u16::MAX as u32) as u16;
+        }
+
+        if let Some(cll) = cll {
+            self.max_content_light_level = cll.max_content_light_level;
+            self.max_frame_average_light_level = cll.max_frame_average_light_level;
+        }
+    }
+}
+
+impl RpuDataHeader {
+    fn parse(reader: &mut BitSliceReader) -> Result<Self> {
+        let rpu_type: u8 = reader.get_n(6)?;
+        ensure!(rpu_type == 2, "Unsupported RPU type {rpu_type}");
+
+        let rpu_format: u16 = reader.get_n(11)?;
+
+        // vdr_rpu_profile, vdr_rpu_level
+        reader.skip_n(8)?;
+
+        let vdr_seq_info_present_flag = reader.get()?;
+        ensure!(
+            vdr_seq_info_present_flag,
+            "RPU without sequence info is not supported"
+        );
+
+        // chroma_resampling_explicit_filter_flag
+        reader.skip_n(1)?;
+
+        let coefficient_data_type = reader.get_n(2)?;
+        let coefficient_log2_denom = if coefficient_data_type == 0 {
+            reader.get_ue()?
+        } else {
+            0
+        };
+
+        // vdr_rpu_normalized_idc, bl_video_full_range_flag
+        reader.skip_n(3)?;
+
+        let mut bl_bit_depth = 8;
+        let mut el_bit_depth = 8;
+        let mut disable_residual_flag = true;
+
+        if rpu_format & 0x700 == 0 {
+            bl_bit_depth += reader.get_ue()? as usize;
+            // The upper bits are ext_mapping_idc
+            el_bit_depth += (reader.get_ue()? & 0xFF) as usize;
+
+            // vdr_bit_depth_minus8
+            reader.get_ue()?;
+
+            // spatial_resampling_filter_flag, reserved_zero_3bits, el_spatial_resampling_filter_flag
+            reader.skip_n(5)?;
+            disable_residual_flag = reader.get()?;
+        }
+
+        let vdr_dm_metadata_present_flag = reader.get()?;
+        let use_prev_vdr_rpu_flag = reader.get()?;
+
+        let mut num_pivots = [0; NUM_COMPONENTS];
+        let mut nlq_method_idc = None;
+
+        if use_prev_vdr_rpu_flag {
+            // prev_vdr_rpu_id
+            reader.get_ue()?;
+        } else {
+            // vdr_rpu_id, mapping_color_space, mapping_chroma_format_idc
+            for _ in 0..3 {
+                reader.get_ue()?;
+            }
+
+            for pivots in num_pivots.iter_mut() {
+                *pivots = reader.get_ue()? as usize + 2;
+                reader.skip_n(*pivots * bl_bit_depth)?;
+            }
+
+            if rpu_format & 0x700 == 0 && !disable_residual_flag {
+                nlq_method_idc = Some(reader.get_n(3)?);
+            }
+
+            // num_x_partitions_minus1, num_y_partitions_minus1
+            reader.get_ue()?;
+            reader.get_ue()?;
+        }
+
+        Ok(Self {
+            coefficient_data_type,
+            coefficient_log2_denom,
+            el_bit_depth,
+            vdr_dm_metadata_present_flag,
+            use_prev_vdr_rpu_flag,
+            num_pivots,
+            nlq_method_idc,
+        })
+    }
+
+    fn skip_mapping(&self, reader: &mut BitSliceReader) -> Result<()> {
+        for pivots in self.num_pivots {
+            for _ in 0..pivots - 1 {
+                let mapping_idc = reader.get_ue()?;
+
+                match mapping_idc {
+                    MAPPING_POLYNOMIAL => {
+                        let poly_order_minus1 = reader.get_ue()?;
+                        if poly_order_minus1 == 0 && reader.get()? {
+                            bail!("Unsupported RPU linear interpolation mapping");
+                        }
+
+                        for _ in 0..=poly_order_minus1 + 1 {
+                            self.skip_coef(reader, true)?;
+                        }
+                    }
+                    MAPPING_MMR => {
+                        let mmr_order_minus1: u8 = reader.get_n(2)?;
+
+                        // mmr_constant
+                        self.skip_coef(reader, true)?;
+
+                        for _ in 0..(mmr_order_minus1 + 1) * 7 {
+                            self.skip_coef(reader, true)?;
+                        }
+                    }
+                    _ => bail!("Invalid RPU mapping_idc {mapping_idc}"),
+                }
+            }
+        }
+
+        if let Some(nlq_method_idc) = self.nlq_method_idc {
+            for _ in 0..NUM_COMPONENTS {
+                // nlq_offset
+                reader.skip_n(self.el_bit_depth)?;
+
+                // vdr_in_max
+                self.skip_coef(reader, false)?;
+
+                if nlq_method_idc == NLQ_LINEAR_DZ {
+                    // linear_deadzone_slope, linear_deadzone_threshold
+                    self.skip_coef(reader, false)?;
+                    self.skip_coef(reader, false)?;
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    fn skip_coef(&self, reader: &mut BitSliceReader, signed: bool) -> Result<()> {
+        if self.coefficient_data_type == 0 {
+            if signed {
+                reader.get_se()?;
+            } else {
+                reader.get_ue()?;
+            }
+
+            reader.skip_n(self.coefficient_log2_denom as usize)
+        } else {
+            reader.skip_n(32)
+        }
+    }
+}
+
+fn skip_vdr_dm_data_payload(reader: &mut BitSliceReader) -> Result<()> {
+    // affected_dm_metadata_id, current_dm_metadata_id, scene_refresh_flag
+    for _ in 0..3 {
+        reader.get_ue()?;
+    }
+
+    // ycc_to_rgb_coef, ycc_to_rgb_offset, rgb_to_lms_coef
+    reader.skip_n(9 * 16 + 3 * 32 + 9 * 16)?;
+
+    // signal_eotf, signal_eotf_param0-2
+    reader.skip_n(16 * 3 + 32)?;
+
+    // signal_bit_depth, signal_color_space, signal_chroma_format, signal_full_range_flag
+    reader.skip_n(5 + 2 + 2 + 2)?;
+
+    // source_min_pq, source_max_pq, source_diagonal
+    reader.skip_n(12 + 12 + 10)
+}
+
+fn parse_ext_blocks(
+    reader: &mut BitSliceReader,
+    ext_blocks: &mut Vec<ExtMetadataBlock>,
+) -> Result<()> {
+    let num_ext_blocks = reader.get_ue()?;
+
+    if num_ext_blocks > 0 {
+        // dm_alignment_zero_bit
+        while !reader.is_aligned() {
+            reader.skip_n(1)?;
+        }
+    }
+
+    for _ in 0..num_ext_blocks {
+        let length = reader.get_ue()? as usize;
+        let level = reader.get_n(8)?;
+
+        ext_blocks.push(ExtMetadataBlock {
+            level,
+            // RPU prefix byte
+            offset: reader.position() + 8,
+        });
+
+        reader.skip_n(length * 8)?;
+    }
+
+    Ok(())
+}
+
+/// Writes the `n` lowest bits of `value` at the bit offset, MSB first
+fn write_bits(data: &mut [u8], offset: usize, n: usize, value: u32) {
+    for i in 0..n {
+        let bit = (value >> (n - 1 - i)) & 1;
+        let pos = offset + i;
+        let mask = 0x80 >> (pos % 8);
+
+        if bit == 1 {
+            data[pos / 8] |= mask;
+        } else {
+            data[pos / 8] &= !mask;
+        }
+    }
+}
+
+/// CRC-32/MPEG-2
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = u32::MAX;
+
+    for &byte in data {
+        crc ^= (byte as u32) << 24;
+
+        for _ in 0..8 {
+            crc = if crc & 0x8000_0000 != 0 {
+                (crc << 1) ^ 0x04C1_1DB7
+            } else {
+                crc << 1
+            };
+        }
+    }
+
+    crc
+}
+
+/// Converts a 12 bit PQ code to nits
+pub fn pq_to_nits(pq: u16) -> f64 {
+    const M1: f64 = 2610.0 / 16384.0;
+    const M2: f64 = (2523.0 / 4096.0) * 128.0;
+    const C1: f64 = 3424.0 / 4096.0;
+    const C2: f64 = (2413.0 / 4096.0) * 32.0;
+    const C3: f64 = (2392.0 / 4096.0) * 32.0;
+
+    let v = (pq as f64 / 4095.0).powf(1.0 / M2);
+    let num = (v - C1).max(0.0);
+    let den = C2 - C3 * v;
+
+    10_000.0 * (num / den).powf(1.0 / M1)
+}
+
+#[cfg(test)]
+mod tests {
+    use bitvec_helpers::bitstream_io_writer::BitstreamIoWriter;
+    use hevc_parser::utils::add_start_code_emulation_prevention_3_byte;
+
+    use super::*;
+
+    /// Profile 8.1 RPU, with polynomial mapping and L1 metadata, optionally followed by L6
+    fn profile81_rpu(with_level6: bool) -> Result<Vec<u8>> {
+        let mut writer = BitstreamIoWriter::with_capacity(128);
+
+        writer.write::<6, u8>(2)?; // rpu_type
+        writer.write::<11, u16>(18)?; // rpu_format
+        writer.write::<4, u8>(1)?; // vdr_rpu_profile
+        writer.write::<4, u8>(0)?; // vdr_rpu_level
+        writer.write_bit(true)?; // vdr_seq_info_present_flag
+        writer.write_bit(false)?; // chroma_resampling_explicit_filter_flag
+        writer.write::<2, u8>(0)?; // coefficient_data_type
+        writer.write_ue(23)?; // coefficient_log2_denom
+        writer.write::<2, u8>(1)?; // vdr_rpu_normalized_idc
+        writer.write_bit(false)?; // bl_video_full_range_flag
+        writer.write_ue(2)?; // bl_bit_depth_minus8
+        writer.write_ue(2)?; // el_bit_depth_minus8
+        writer.write_ue(4)?; // vdr_bit_depth_minus8
+        writer.write_bit(false)?; // spatial_resampling_filter_flag
+        writer.write::<3, u8>(0)?; // reserved_zero_3bits
+        writer.write_bit(false)?; // el_spatial_resampling_filter_flag
+        writer.write_bit(true)?; // disable_residual_flag
+        writer.write_bit(true)?; // vdr_dm_metadata_present_flag
+        writer.write_bit(false)?; // use_prev_vdr_rpu_flag
+        writer.write_ue(0)?; // vdr_rpu_id
+        writer.write_ue(0)?; // mapping_color_space
+        writer.write_ue(0)?; // mapping_chroma_format_idc
+
+        for _ in 0..NUM_COMPONENTS {
+            writer.write_ue(0)?; // num_pivots_minus_2
+            writer.write::<10, u16>(0)?;
+            writer.write::<10, u16>(1023)?;
+        }
+
+        writer.write_ue(0)?; // num_x_partitions_minus1
+        writer.write_ue(0)?; // num_y_partitions_minus1
+
+        // mapping
+        for _ in 0..NUM_COMPONENTS {
+            writer.write_ue(MAPPING_POLYNOMIAL)?;
+            writer.write_ue(0)?; // poly_order_minus1
+            writer.write_bit(false)?; // linear_interp_flag
+
+            for coef in [0, 1] {
+                writer.write_se(coef)?;
+                writer.write::<23, u32>(0)?;
+            }
+        }
+
+        // vdr_dm_data_payload
+        writer.write_ue(0)?;
+        writer.write_ue(0)?;
+        writer.write_ue(1)?;
+        for _ in 0..9 {
+            writer.write::<16, u16>(0)?;
+        }
+        for _ in 0..3 {
+            writer.write::<32, u32>(0)?;
+        }
+        for _ in 0..9 {
+            writer.write::<16, u16>(0)?;
+        }
+        writer.write::<16, u16>(65535)?;
+        writer.write::<16, u16>(0)?;
+        writer.write::<16, u16>(0)?;
+        writer.write::<32, u32>(0)?;
+        writer.write::<5, u8>(12)?;
+        writer.write::<2, u8>(0)?;
+        writer.write::<2, u8>(0)?;
+        writer.write::<2, u8>(0)?;
+        writer.write::<12, u16>(62)?;
+        writer.write::<12, u16>(3079)?;
+        writer.write::<10, u16>(42)?;
+
+        writer.write_ue(if with_level6 { 2 } else { 1 })?; // num_ext_blocks
+        writer.byte_align()?;
+
+        // L1
+        writer.write_ue(5)?;
+        writer.write::<8, u8>(1)?;
+        writer.write::<12, u16>(0)?;
+        writer.write::<12, u16>(2081)?;
+        writer.write::<12, u16>(1229)?;
+        writer.write::<4, u8>(0)?;
+
+        // L6
+        if with_level6 {
+            writer.write_ue(8)?;
+            writer.write::<8, u8>(6)?;
+            writer.write::<16, u16>(1000)?;
+            writer.write::<16, u16>(1)?;
+            writer.write::<16, u16>(1000)?;
+            writer.write::<16, u16>(400)?;
+        }
+
+        writer.byte_align()?;
+
+        let payload = writer.into_inner();
+
+        let mut data = vec![RPU_PREFIX];
+        data.extend_from_slice(&payload);
+        data.extend_from_slice(&crc32(&payload).to_be_bytes());
+        data.push(FINAL_BYTE);
+
+        add_start_code_emulation_prevention_3_byte(&mut data);
+
+        let mut nal = NAL_HEADER.to_vec();
+        nal.extend_from_slice(&data);
+
+        Ok(nal)
+    }
+
+    #[test]
+    fn test_crc32() {
+        // Check value of the CRC-32/MPEG-2 catalogue entry, independent of the RPU writer
+        assert_eq!(crc32(b"123456789"), 0x0376_E6E7);
+        assert_eq!(crc32(&[]), u32::MAX);
+    }
+
+    #[test]
+    fn test_parse_ext_blocks() -> Result<()> {
+        let rpu = DoviRpu::parse_unspec62_nalu(&profile81_rpu(true)?)?;
+
+        let levels: Vec<u8> = rpu.ext_blocks.iter().map(|b| b.level).collect();
+        assert_eq!(levels, [1, 6]);
+
+        let level1 = rpu.level1()?.unwrap();
+        assert_eq!(
+            level1,
+            ExtMetadataBlockLevel1 {
+                min_pq: 0,
+                max_pq: 2081,
+                avg_pq: 1229,
+            }
+        );
+
+        assert_eq!(pq_to_nits(2081).round(), 100.0);
+        assert_eq!(pq_to_nits(4095).round(), 10_000.0);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_level6_rewrite() -> Result<()> {
+        let original = profile81_rpu(true)?;
+        let mut rpu = DoviRpu::parse_unspec62_nalu(&original)?;
+
+        let level6 = rpu.level6()?.unwrap();
+        assert_eq!(
+            level6,
+            ExtMetadataBlockLevel6 {
+                max_display_mastering_luminance: 1000,
+                min_display_mastering_luminance: 1,
+                max_content_light_level: 1000,
+                max_frame_average_light_level: 400,
+            }
+        );
+
+        // unchanged values round trip back to the original
+        assert!(rpu.set_level6(&level6));
+        assert_eq!(rpu.write_unspec62_nalu(), original);
+
+        let new_level6 = ExtMetadataBlockLevel6 {
+            max_display_mastering_luminance: 4000,
+            min_display_mastering_luminance: 50,
+            max_content_light_level: 2800,
+            max_frame_average_light_level: 225,
+        };
+        assert!(rpu.set_level6(&new_level6));
+
+        // CRC is validated when parsing
+        let rewritten = DoviRpu::parse_unspec62_nalu(&rpu.write_unspec62_nalu())?;
+        assert_eq!(rewritten.level6()?, Some(new_level6));
+        assert_eq!(rewritten.level1()?, rpu.level1()?);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_level6_missing() -> Result<()> {
+        let original = profile81_rpu(false)?;
+        let mut rpu = DoviRpu::parse_unspec62_nalu(&original)?;
+
+        assert_eq!(rpu.level6()?, None);
+        assert!(rpu.level1()?.is_some());
+
+        // Not inserted
+        let level6 = ExtMetadataBlockLevel6 {
+            max_display_mastering_luminance: 1000,
+            min_display_mastering_luminance: 1,
+            max_content_light_level: 1000,
+            max_frame_average_light_level: 400,
+        };
+        assert!(!rpu.set_level6(&level6));
+        assert_eq!(rpu.write_unspec62_nalu(), original);
+
+        Ok(())
+    }
+}
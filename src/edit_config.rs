@@ -1,19 +1,43 @@
 use std::fs::File;
-use std::path::Path;
+use std::path::{Path, PathBuf};
 
-use anyhow::{Result, bail};
+use anyhow::{Result, anyhow, bail};
 use serde::{Deserialize, Serialize};
 
-use super::mdcv_metadata::{MasteringDisplayPrimaries, MdcvPrimariesPreset};
+use super::ave_metadata::AveMetadata;
+use super::ccv_metadata::{CCV_LUMINANCE_FACTOR, ContentColourVolumePrimaries};
+use super::dovi_rpu::ExtMetadataBlockLevel6;
+use super::mdcv_metadata::{MDL_FACTOR, MasteringDisplayPrimaries, MdcvPrimariesPreset};
+use super::sps_vui::VideoSignalType;
+use super::utils::{decode_base64, decode_hex, encode_hex};
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
 pub struct EditConfig {
     pub mdcv: Option<EditMdcvMetadata>,
     pub cll: Option<EditCllMetadata>,
+    pub atc: Option<EditAtcMetadata>,
+    pub ave: Option<EditAveMetadata>,
+    pub ccv: Option<EditCcvMetadata>,
+    pub hdr10plus: Option<EditHdr10PlusMetadata>,
+    pub udu: Option<EditUduConfig>,
+
+    /// Generic rules for any SEI payload type, applied before the edits above
+    #[serde(default)]
+    pub sei_rules: Vec<SeiRule>,
+
+    pub dolby_vision: Option<EditDoviConfig>,
+
+    pub vui: Option<EditVuiMetadata>,
+
+    pub provenance: Option<EditProvenanceConfig>,
 }
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
 pub struct EditMdcvMetadata {
+    /// Only edit the messages matching the conditions
+    #[serde(rename = "match")]
+    pub match_condition: Option<MdcvMatchCondition>,
+
     /// Existing preset display primaries (BT.709, Display-P3 or BT.2020)
     pub preset: Option<MdcvPrimariesPreset>,
 
@@ -24,12 +48,245 @@ pub struct EditMdcvMetadata {
     /// Example: min: 0.001 nits, max: 1000 nits
     pub max_display_mastering_luminance: Option<f32>,
     pub min_display_mastering_luminance: Option<f32>,
+
+    /// Transforms applied in order, on top of the edited values
+    #[serde(default)]
+    pub transforms: Vec<MdcvTransform>,
 }
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
 pub struct EditCllMetadata {
+    /// Compute the values from the stream's dynamic metadata, overriding the values below.
+    /// Requires a first pass over the input.
+    pub source: Option<CllSource>,
+
+    /// Only edit the messages matching the conditions
+    #[serde(rename = "match")]
+    pub match_condition: Option<CllMatchCondition>,
+
     pub max_content_light_level: Option<u16>,
     pub max_frame_average_light_level: Option<u16>,
+
+    /// Transforms applied in order, on top of the edited values
+    #[serde(default)]
+    pub transforms: Vec<CllTransform>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct EditAtcMetadata {
+    /// ITU-T H.273 transfer characteristics, usually 18 for HLG
+    pub preferred_transfer_characteristics: Option<u8>,
+
+    /// Inserts the message in access units with a SPS that don't have it
+    #[serde(default)]
+    pub insert: bool,
+
+    /// Removes every message
+    #[serde(default)]
+    pub remove: bool,
+}
+
+/// All values are required for insertion
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct EditAveMetadata {
+    /// In units of 0.0001 lux
+    pub ambient_illuminance: Option<u32>,
+
+    /// CIE 1931 chromaticity of the ambient light, in units of 0.00002
+    pub ambient_light_x: Option<u16>,
+    pub ambient_light_y: Option<u16>,
+
+    /// Inserts the message in access units with a SPS that don't have it
+    #[serde(default)]
+    pub insert: bool,
+
+    /// Removes every message
+    #[serde(default)]
+    pub remove: bool,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct EditCcvMetadata {
+    /// Existing preset primaries (BT.709, Display-P3 or BT.2020)
+    pub preset: Option<MdcvPrimariesPreset>,
+
+    /// `Some` to edit, `None` to leave untouched
+    pub primaries: Option<ContentColourVolumePrimaries>,
+
+    /// In nits, up to ~429 nits
+    pub min_luminance_value: Option<f64>,
+    pub max_luminance_value: Option<f64>,
+    pub avg_luminance_value: Option<f64>,
+
+    /// Inserts the message in access units with a SPS that don't have it
+    #[serde(default)]
+    pub insert: bool,
+
+    /// Removes every message
+    #[serde(default)]
+    pub remove: bool,
+}
+
+/// Static fields of the ST 2094-40 metadata, the dynamic metadata is left untouched
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct EditHdr10PlusMetadata {
+    pub application_version: Option<u8>,
+
+    /// In nits
+    pub targeted_system_display_maximum_luminance: Option<u32>,
+
+    /// Sets the targeted system display maximum luminance to the MDCV max luminance
+    /// of the access unit, overriding the value above
+    #[serde(default)]
+    pub tsdml_from_mdcv: bool,
+
+    /// Samsung HDR10+ JSON metadata to inject in every frame, replacing the existing metadata.
+    /// The edits above are applied on top of it.
+    pub inject: Option<PathBuf>,
+
+    /// Removes every HDR10+ message
+    #[serde(default)]
+    pub remove: bool,
+}
+
+/// User data unregistered SEI messages, usually encoder information
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct EditUduConfig {
+    /// Removes the messages matching the UUIDs, or every message if there are none
+    #[serde(default)]
+    pub remove: bool,
+
+    #[serde(default)]
+    pub uuids: Vec<SeiUuid>,
+}
+
+/// UUID in hex, dashes are ignored
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(try_from = "String", into = "String")]
+pub struct SeiUuid([u8; 16]);
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct SeiRule {
+    pub payload_type: u8,
+    pub action: SeiRuleAction,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum SeiRuleAction {
+    /// Leaves the messages untouched, ignoring the following rules and edits for the payload type
+    Keep,
+    /// Removes the messages
+    Drop,
+    /// Replaces the payload of the messages
+    Replace(SeiRulePayload),
+    /// Inserts the payload in access units with a SPS that don't have the payload type
+    Insert(SeiRulePayload),
+}
+
+/// Raw SEI payload, from a hex or base64 string
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(try_from = "RawSeiRulePayload", into = "RawSeiRulePayload")]
+pub struct SeiRulePayload(Vec<u8>);
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "snake_case")]
+enum RawSeiRulePayload {
+    Hex(String),
+    Base64(String),
+}
+
+/// Record of the MDCV/CLL edits, in a user data unregistered SEI
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct EditProvenanceConfig {
+    /// Inserts the original and edited metadata before the edited messages
+    #[serde(default)]
+    pub insert: bool,
+
+    /// Restores the original metadata from the records, and removes them
+    #[serde(default)]
+    pub revert: bool,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct EditDoviConfig {
+    /// Keeps the RPU L6 metadata consistent with the HDR10 metadata
+    pub l6: Option<DoviL6Sync>,
+
+    /// Removes the RPU and enhancement layer NALs
+    #[serde(default)]
+    pub remove: bool,
+}
+
+/// Colour signalling of the SPS VUI, as ITU-T H.273 code points.
+/// `None` values keep the existing signalling.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct EditVuiMetadata {
+    pub video_full_range_flag: Option<bool>,
+    pub colour_primaries: Option<u8>,
+    pub transfer_characteristics: Option<u8>,
+    pub matrix_coeffs: Option<u8>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum DoviL6Sync {
+    /// Rewrites the L6 metadata of every RPU from the written MDCV/CLL metadata
+    FromHdr10,
+    /// Replaces the MDCV luminance and CLL values with the L6 metadata of the first RPU
+    ToHdr10,
+}
+
+/// Conditions on the existing MDCV metadata.
+/// Every present value must be equal for the message to match.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct MdcvMatchCondition {
+    pub preset: Option<MdcvPrimariesPreset>,
+    pub primaries: Option<MasteringDisplayPrimaries>,
+
+    /// In nits
+    pub max_display_mastering_luminance: Option<f32>,
+    pub min_display_mastering_luminance: Option<f32>,
+}
+
+/// Conditions on the existing CLL metadata.
+/// Every present value must be equal for the message to match.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct CllMatchCondition {
+    pub max_content_light_level: Option<u16>,
+    pub max_frame_average_light_level: Option<u16>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CllSource {
+    /// Max of the per-frame MaxSCL and average MaxRGB from ST 2094-40 metadata
+    #[serde(rename = "hdr10plus")]
+    #[serde(alias = "hdr10+")]
+    Hdr10Plus,
+    /// Max of the per-shot L1 max and average PQ from Dolby Vision RPUs
+    #[serde(rename = "dolby_vision")]
+    #[serde(alias = "dovi")]
+    DolbyVision,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum MdcvTransform {
+    /// Multiplies both the max and min luminance by the factor
+    ScaleLuminance(f32),
+    /// Rounds the min luminance to the closest commonly used value
+    RoundMinLuminance,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum CllTransform {
+    /// Clamps MaxCLL and MaxFALL to the MDCV max luminance of the access unit
+    ClampToMdcv,
+    /// Multiplies both MaxCLL and MaxFALL by the factor
+    Scale(f32),
+    /// Ensures MaxFALL is not greater than MaxCLL
+    LimitMaxFall,
 }
 
 impl EditConfig {
@@ -41,10 +298,283 @@ impl EditConfig {
     }
 
     pub fn setup(&mut self) -> Result<()> {
-        if self.mdcv.is_none() && self.cll.is_none() {
-            bail!("One of either MDCV or CLL metadata must be present");
+        if self.mdcv.is_none()
+            && self.cll.is_none()
+            && self.atc.is_none()
+            && self.ave.is_none()
+            && self.ccv.is_none()
+            && self.hdr10plus.is_none()
+            && self.udu.is_none()
+            && self.sei_rules.is_empty()
+            && self.dolby_vision.is_none()
+            && self.vui.is_none()
+            && !self.revert_provenance()
+        {
+            bail!("No edits present in the config");
+        }
+
+        if let Some(provenance) = self.provenance.as_ref() {
+            if provenance.insert && provenance.revert {
+                bail!("Provenance cannot be both inserted and reverted");
+            }
+
+            if provenance.insert && self.mdcv.is_none() && self.cll.is_none() {
+                bail!("Provenance insertion requires MDCV or CLL edits");
+            }
+
+            if provenance.revert && (self.mdcv.is_some() || self.cll.is_some()) {
+                bail!("Provenance revert cannot be combined with MDCV or CLL edits");
+            }
+        }
+
+        if let Some(atc) = self.atc.as_ref() {
+            if atc.remove && (atc.insert || atc.preferred_transfer_characteristics.is_some()) {
+                bail!("ATC cannot be both removed and edited");
+            }
+
+            if atc.insert && atc.preferred_transfer_characteristics.is_none() {
+                bail!("ATC insertion requires preferred_transfer_characteristics");
+            }
+        }
+
+        if let Some(ave) = self.ave.as_ref() {
+            let has_values = ave.ambient_illuminance.is_some()
+                || ave.ambient_light_x.is_some()
+                || ave.ambient_light_y.is_some();
+
+            if ave.remove && (ave.insert || has_values) {
+                bail!("AVE cannot be both removed and edited");
+            }
+
+            if ave.insert && AveMetadata::from_edit(ave).is_none() {
+                bail!("AVE insertion requires all of the values");
+            }
+        }
+
+        if let Some(ccv) = self.ccv.as_ref() {
+            let luminance_values = [
+                ccv.min_luminance_value,
+                ccv.max_luminance_value,
+                ccv.avg_luminance_value,
+            ];
+            let has_values = ccv.preset.is_some()
+                || ccv.primaries.is_some()
+                || luminance_values.iter().any(Option::is_some);
+
+            if ccv.remove && (ccv.insert || has_values) {
+                bail!("CCV cannot be both removed and edited");
+            }
+
+            if ccv.insert && !has_values {
+                bail!("CCV insertion requires primaries or luminance values");
+            }
+
+            let max_nits = u32::MAX as f64 / CCV_LUMINANCE_FACTOR;
+            if luminance_values
+                .into_iter()
+                .flatten()
+                .any(|nits| !(0.0..=max_nits).contains(&nits))
+            {
+                bail!("CCV luminance values must be between 0 and {max_nits:.2} nits");
+            }
+        }
+
+        if let Some(hdr10plus) = self.hdr10plus.as_ref() {
+            let has_values = hdr10plus.application_version.is_some()
+                || hdr10plus
+                    .targeted_system_display_maximum_luminance
+                    .is_some()
+                || hdr10plus.tsdml_from_mdcv
+                || hdr10plus.inject.is_some();
+
+            if hdr10plus.remove && has_values {
+                bail!("HDR10+ cannot be both removed and edited");
+            }
+
+            if hdr10plus
+                .targeted_system_display_maximum_luminance
+                .is_some_and(|tsdml| tsdml > 10_000)
+            {
+                bail!(
+                    "HDR10+ targeted system display maximum luminance must be at most 10000 nits"
+                );
+            }
+        }
+
+        if self.l6_sync() == Some(DoviL6Sync::ToHdr10)
+            && self.cll.as_ref().is_some_and(|cll| cll.source.is_some())
+        {
+            bail!("CLL source cannot be used when copying the L6 metadata to HDR10");
         }
 
         Ok(())
     }
+
+    /// Whether the MDCV metadata of the access unit is needed for editing
+    pub fn requires_mdcv(&self) -> bool {
+        self.cll.as_ref().is_some_and(|cll| cll.requires_mdcv())
+            || self.hdr10plus.as_ref().is_some_and(|e| e.tsdml_from_mdcv)
+    }
+
+    /// Edits using the last MDCV/CLL metadata written, even when not edited
+    pub fn tracks_hdr10_metadata(&self) -> bool {
+        self.requires_mdcv() || self.l6_sync() == Some(DoviL6Sync::FromHdr10)
+    }
+
+    /// Action of the first rule for the payload type, ignoring insertions
+    pub fn sei_rule_action(&self, payload_type: u8) -> Option<&SeiRuleAction> {
+        self.sei_rules
+            .iter()
+            .filter(|rule| rule.payload_type == payload_type)
+            .map(|rule| &rule.action)
+            .find(|action| !matches!(action, SeiRuleAction::Insert(_)))
+    }
+
+    /// Values computed from a first pass over the input
+    pub fn requires_scan(&self) -> bool {
+        self.cll.as_ref().is_some_and(|e| e.source.is_some())
+            || self.l6_sync() == Some(DoviL6Sync::ToHdr10)
+            || self.hdr10plus.as_ref().is_some_and(|e| e.inject.is_some())
+    }
+
+    /// Existing provenance records are replaced or consumed, and kept otherwise
+    pub fn edits_provenance(&self) -> bool {
+        self.insert_provenance() || self.revert_provenance()
+    }
+
+    pub fn insert_provenance(&self) -> bool {
+        self.provenance.as_ref().is_some_and(|e| e.insert)
+    }
+
+    pub fn revert_provenance(&self) -> bool {
+        self.provenance.as_ref().is_some_and(|e| e.revert)
+    }
+
+    pub fn remove_dovi(&self) -> bool {
+        self.dolby_vision.as_ref().is_some_and(|dovi| dovi.remove)
+    }
+
+    pub fn l6_sync(&self) -> Option<DoviL6Sync> {
+        self.dolby_vision.as_ref().and_then(|dovi| dovi.l6)
+    }
+
+    /// Sets the MDCV luminance and CLL values from the Dolby Vision L6 metadata.
+    /// Values set in the config are kept.
+    pub fn copy_level6(&mut self, level6: &ExtMetadataBlockLevel6) {
+        let mdcv = self.mdcv.get_or_insert_with(Default::default);
+        mdcv.max_display_mastering_luminance
+            .get_or_insert(level6.max_display_mastering_luminance as f32);
+        mdcv.min_display_mastering_luminance
+            .get_or_insert(level6.min_display_mastering_luminance as f32 / MDL_FACTOR);
+
+        let cll = self.cll.get_or_insert_with(Default::default);
+        cll.max_content_light_level
+            .get_or_insert(level6.max_content_light_level);
+        cll.max_frame_average_light_level
+            .get_or_insert(level6.max_frame_average_light_level);
+    }
+}
+
+impl EditCllMetadata {
+    pub fn requires_mdcv(&self) -> bool {
+        self.transforms.contains(&CllTransform::ClampToMdcv)
+    }
+}
+
+impl EditVuiMetadata {
+    /// Edited video signal type, inserted with unspecified values if not present
+    pub fn apply(&self, existing: Option<VideoSignalType>) -> VideoSignalType {
+        let mut signal = existing.unwrap_or_default();
+
+        if let Some(video_full_range_flag) = self.video_full_range_flag {
+            signal.video_full_range_flag = video_full_range_flag;
+        }
+
+        let colour_description = [
+            (self.colour_primaries, &mut signal.colour_primaries),
+            (
+                self.transfer_characteristics,
+                &mut signal.transfer_characteristics,
+            ),
+            (self.matrix_coeffs, &mut signal.matrix_coeffs),
+        ];
+
+        for (value, field) in colour_description {
+            if let Some(value) = value {
+                *field = value;
+                signal.colour_description_present_flag = true;
+            }
+        }
+
+        signal
+    }
+}
+
+impl EditUduConfig {
+    /// `data` is the user data unregistered payload, starting with the UUID
+    pub fn should_remove(&self, data: &[u8]) -> bool {
+        self.remove
+            && (self.uuids.is_empty()
+                || data
+                    .get(..16)
+                    .is_some_and(|uuid| self.uuids.iter().any(|e| e.0 == uuid)))
+    }
+}
+
+impl TryFrom<String> for SeiUuid {
+    type Error = anyhow::Error;
+
+    fn try_from(value: String) -> Result<Self> {
+        decode_hex(&value.replace('-', ""))
+            .ok()
+            .and_then(|data| data.try_into().ok())
+            .map(Self)
+            .ok_or_else(|| anyhow!("Invalid UUID {value}, expected 32 hex digits"))
+    }
+}
+
+impl From<SeiUuid> for String {
+    fn from(uuid: SeiUuid) -> Self {
+        encode_hex(&uuid.0)
+    }
+}
+
+impl SeiRulePayload {
+    pub fn data(&self) -> &[u8] {
+        &self.0
+    }
+}
+
+impl From<Vec<u8>> for SeiRulePayload {
+    fn from(data: Vec<u8>) -> Self {
+        Self(data)
+    }
+}
+
+impl TryFrom<RawSeiRulePayload> for SeiRulePayload {
+    type Error = anyhow::Error;
+
+    fn try_from(value: RawSeiRulePayload) -> Result<Self> {
+        let data = match value {
+            RawSeiRulePayload::Hex(hex) => decode_hex(&hex)?,
+            RawSeiRulePayload::Base64(base64) => decode_base64(&base64)?,
+        };
+
+        Ok(Self(data))
+    }
+}
+
+impl From<SeiRulePayload> for RawSeiRulePayload {
+    fn from(payload: SeiRulePayload) -> Self {
+        Self::Hex(encode_hex(&payload.0))
+    }
+}
+
+impl std::fmt::Display for CllSource {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Hdr10Plus => write!(f, "HDR10+"),
+            Self::DolbyVision => write!(f, "Dolby Vision L1"),
+        }
+    }
 }
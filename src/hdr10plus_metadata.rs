@@ -0,0 +1,597 @@
+use std::fs::File;
+use std::io::BufReader;
+use std::path::Path;
+
+use anyhow::{Result, bail, ensure};
+use bitvec_helpers::{bitslice_reader::BitSliceReader, bitstream_io_writer::BitstreamIoWriter};
+use serde::{Deserialize, Serialize};
+
+use super::edit_config::EditHdr10PlusMetadata;
+use super::mdcv_metadata::MdcvMetadata;
+
+const ITU_T_T35_COUNTRY_CODE: u8 = 0xB5;
+const ITU_T_T35_TERMINAL_PROVIDER_CODE: u16 = 0x003C;
+const ITU_T_T35_TERMINAL_PROVIDER_ORIENTED_CODE: u16 = 0x0001;
+const APPLICATION_IDENTIFIER: u8 = 4;
+
+/// Luminance values are in units of 0.1 nits
+const LUMINANCE_FACTOR: f64 = 10.0;
+
+/// Application version written for injected metadata
+const INJECTED_APPLICATION_VERSION: u8 = 1;
+
+/// SMPTE ST 2094-40 dynamic metadata, from a registered ITU-T T.35 SEI message
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Hdr10PlusMetadata {
+    pub application_version: u8,
+    pub num_windows: u8,
+
+    /// Windows after the first one, which is always the full frame
+    pub processing_windows: Vec<ProcessingWindow>,
+
+    pub targeted_system_display_maximum_luminance: u32,
+    pub targeted_system_display_actual_peak_luminance: Option<ActualPeakLuminance>,
+
+    /// One per window
+    pub luminance_parameters: Vec<LuminanceParameters>,
+
+    pub mastering_display_actual_peak_luminance: Option<ActualPeakLuminance>,
+
+    /// One per window
+    pub bezier_curves: Vec<Option<BezierCurve>>,
+
+    /// One per window
+    pub color_saturation_weights: Vec<Option<u8>>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct ProcessingWindow {
+    pub window_upper_left_corner_x: u16,
+    pub window_upper_left_corner_y: u16,
+    pub window_lower_right_corner_x: u16,
+    pub window_lower_right_corner_y: u16,
+
+    pub center_of_ellipse_x: u16,
+    pub center_of_ellipse_y: u16,
+    pub rotation_angle: u8,
+
+    pub semimajor_axis_internal_ellipse: u16,
+    pub semimajor_axis_external_ellipse: u16,
+    pub semiminor_axis_external_ellipse: u16,
+    pub overlap_process_option: bool,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct ActualPeakLuminance {
+    pub num_rows: u8,
+    pub num_cols: u8,
+    pub values: Vec<u8>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct LuminanceParameters {
+    /// In r,g,b order, units of 0.1 nits
+    pub maxscl: [u32; 3],
+    pub average_maxrgb: u32,
+
+    /// Pairs of percentage and percentile
+    pub distribution_maxrgb: Vec<(u8, u32)>,
+
+    pub fraction_bright_pixels: u16,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct BezierCurve {
+    pub knee_point_x: u16,
+    pub knee_point_y: u16,
+    pub bezier_curve_anchors: Vec<u16>,
+}
+
+/// Samsung HDR10+ JSON metadata, with one scene info per frame in presentation order
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "PascalCase")]
+struct Hdr10PlusJson {
+    scene_info: Vec<JsonSceneInfo>,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "PascalCase")]
+struct JsonSceneInfo {
+    number_of_windows: u8,
+    targeted_system_display_maximum_luminance: u32,
+    luminance_parameters: JsonLuminanceParameters,
+
+    /// Only present for profile B
+    bezier_curve_data: Option<JsonBezierCurve>,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "PascalCase")]
+struct JsonLuminanceParameters {
+    #[serde(rename = "AverageRGB")]
+    average_rgb: u32,
+    max_scl: [u32; 3],
+    luminance_distributions: JsonLuminanceDistributions,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "PascalCase")]
+struct JsonLuminanceDistributions {
+    distribution_index: Vec<u8>,
+    distribution_values: Vec<u32>,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "PascalCase")]
+struct JsonBezierCurve {
+    knee_point_x: u16,
+    knee_point_y: u16,
+    anchors: Vec<u16>,
+}
+
+impl Hdr10PlusMetadata {
+    /// Per-frame metadata from a Samsung HDR10+ JSON file, in presentation order
+    pub fn from_json_path<P: AsRef<Path>>(path: P) -> Result<Vec<Self>> {
+        let json: Hdr10PlusJson = serde_json::from_reader(BufReader::new(File::open(path)?))?;
+
+        json.scene_info.into_iter().map(Self::try_from).collect()
+    }
+
+    /// Whether the registered ITU-T T.35 payload is HDR10+ metadata
+    pub fn is_hdr10plus(data: &[u8]) -> bool {
+        let mut reader = BitSliceReader::new(data);
+
+        let mut header = || -> Result<(u8, u16, u16, u8)> {
+            Ok((
+                reader.get_n(8)?,
+                reader.get_n(16)?,
+                reader.get_n(16)?,
+                reader.get_n(8)?,
+            ))
+        };
+
+        header().is_ok_and(|header| {
+            header
+                == (
+                    ITU_T_T35_COUNTRY_CODE,
+                    ITU_T_T35_TERMINAL_PROVIDER_CODE,
+                    ITU_T_T35_TERMINAL_PROVIDER_ORIENTED_CODE,
+                    APPLICATION_IDENTIFIER,
+                )
+        })
+    }
+
+    pub fn parse(data: &[u8]) -> Result<Self> {
+        ensure!(Self::is_hdr10plus(data), "Not HDR10+ metadata");
+
+        let mut reader = BitSliceReader::new(data);
+        // T.35 header and application_identifier
+        reader.skip_n(48)?;
+
+        let application_version = reader.get_n(8)?;
+        let num_windows: u8 = reader.get_n(2)?;
+        ensure!(num_windows > 0, "Invalid num_windows 0");
+
+        let processing_windows = (1..num_windows)
+            .map(|_| ProcessingWindow::parse(&mut reader))
+            .collect::<Result<_>>()?;
+
+        let targeted_system_display_maximum_luminance = reader.get_n(27)?;
+        let targeted_system_display_actual_peak_luminance =
+            ActualPeakLuminance::parse_if_present(&mut reader)?;
+
+        let luminance_parameters = (0..num_windows)
+            .map(|_| LuminanceParameters::parse(&mut reader))
+            .collect::<Result<_>>()?;
+
+        let mastering_display_actual_peak_luminance =
+            ActualPeakLuminance::parse_if_present(&mut reader)?;
+
+        let mut bezier_curves = Vec::with_capacity(num_windows as usize);
+        let mut color_saturation_weights = Vec::with_capacity(num_windows as usize);
+
+        for _ in 0..num_windows {
+            let tone_mapping_flag = reader.get()?;
+            bezier_curves.push(if tone_mapping_flag {
+                Some(BezierCurve::parse(&mut reader)?)
+            } else {
+                None
+            });
+
+            let color_saturation_mapping_flag = reader.get()?;
+            color_saturation_weights.push(if color_saturation_mapping_flag {
+                Some(reader.get_n(6)?)
+            } else {
+                None
+            });
+        }
+
+        Ok(Self {
+            application_version,
+            num_windows,
+            processing_windows,
+            targeted_system_display_maximum_luminance,
+            targeted_system_display_actual_peak_luminance,
+            luminance_parameters,
+            mastering_display_actual_peak_luminance,
+            bezier_curves,
+            color_saturation_weights,
+        })
+    }
+
+    pub fn encode(&self) -> Result<Vec<u8>> {
+        let mut writer = BitstreamIoWriter::with_capacity(64);
+
+        writer.write::<8, u8>(ITU_T_T35_COUNTRY_CODE)?;
+        writer.write::<16, u16>(ITU_T_T35_TERMINAL_PROVIDER_CODE)?;
+        writer.write::<16, u16>(ITU_T_T35_TERMINAL_PROVIDER_ORIENTED_CODE)?;
+        writer.write::<8, u8>(APPLICATION_IDENTIFIER)?;
+
+        writer.write::<8, u8>(self.application_version)?;
+        writer.write::<2, u8>(self.num_windows)?;
+
+        for window in &self.processing_windows {
+            window.write(&mut writer)?;
+        }
+
+        writer.write::<27, u32>(self.targeted_system_display_maximum_luminance)?;
+        ActualPeakLuminance::write_if_present(
+            &mut writer,
+            self.targeted_system_display_actual_peak_luminance.as_ref(),
+        )?;
+
+        for luminance_parameters in &self.luminance_parameters {
+            luminance_parameters.write(&mut writer)?;
+        }
+
+        ActualPeakLuminance::write_if_present(
+            &mut writer,
+            self.mastering_display_actual_peak_luminance.as_ref(),
+        )?;
+
+        for (bezier_curve, color_saturation_weight) in self
+            .bezier_curves
+            .iter()
+            .zip(&self.color_saturation_weights)
+        {
+            writer.write_bit(bezier_curve.is_some())?;
+            if let Some(bezier_curve) = bezier_curve {
+                bezier_curve.write(&mut writer)?;
+            }
+
+            writer.write_bit(color_saturation_weight.is_some())?;
+            if let Some(color_saturation_weight) = color_saturation_weight {
+                writer.write::<6, u8>(*color_saturation_weight)?;
+            }
+        }
+
+        writer.byte_align()?;
+
+        Ok(writer.into_inner())
+    }
+
+    /// The MDCV metadata is required to set the targeted display luminance from it
+    pub fn copy(mut self, src: &EditHdr10PlusMetadata, mdcv: Option<&MdcvMetadata>) -> Self {
+        if let Some(application_version) = src.application_version {
+            self.application_version = application_version;
+        }
+
+        if let Some(tsdml) = src.targeted_system_display_maximum_luminance {
+            self.targeted_system_display_maximum_luminance = tsdml;
+        }
+
+        if src.tsdml_from_mdcv
+            && let Some(mdcv) = mdcv
+        {
+            self.targeted_system_display_maximum_luminance = mdcv.max_luminance().round() as u32;
+        }
+
+        self
+    }
+
+    /// Brightest component of the frame, in nits
+    pub fn max_content_light_level(&self) -> f64 {
+        let maxscl = self.luminance_parameters[0].maxscl;
+        maxscl.into_iter().max().unwrap_or(0) as f64 / LUMINANCE_FACTOR
+    }
+
+    /// Average light level of the frame, in nits
+    pub fn frame_average_light_level(&self) -> f64 {
+        self.luminance_parameters[0].average_maxrgb as f64 / LUMINANCE_FACTOR
+    }
+}
+
+impl TryFrom<JsonSceneInfo> for Hdr10PlusMetadata {
+    type Error = anyhow::Error;
+
+    fn try_from(scene_info: JsonSceneInfo) -> Result<Self> {
+        if scene_info.number_of_windows != 1 {
+            bail!(
+                "Unsupported HDR10+ JSON metadata with {} windows",
+                scene_info.number_of_windows
+            );
+        }
+
+        let JsonLuminanceParameters {
+            average_rgb,
+            max_scl,
+            luminance_distributions,
+        } = scene_info.luminance_parameters;
+
+        ensure!(
+            luminance_distributions.distribution_index.len()
+                == luminance_distributions.distribution_values.len(),
+            "Mismatched HDR10+ JSON luminance distributions"
+        );
+
+        let distribution_maxrgb = luminance_distributions
+            .distribution_index
+            .into_iter()
+            .zip(luminance_distributions.distribution_values)
+            .collect();
+
+        let bezier_curve = scene_info.bezier_curve_data.map(|curve| BezierCurve {
+            knee_point_x: curve.knee_point_x,
+            knee_point_y: curve.knee_point_y,
+            bezier_curve_anchors: curve.anchors,
+        });
+
+        Ok(Self {
+            application_version: INJECTED_APPLICATION_VERSION,
+            num_windows: 1,
+            processing_windows: Vec::new(),
+            targeted_system_display_maximum_luminance: scene_info
+                .targeted_system_display_maximum_luminance,
+            targeted_system_display_actual_peak_luminance: None,
+            luminance_parameters: vec![LuminanceParameters {
+                maxscl: max_scl,
+                average_maxrgb: average_rgb,
+                distribution_maxrgb,
+                fraction_bright_pixels: 0,
+            }],
+            mastering_display_actual_peak_luminance: None,
+            bezier_curves: vec![bezier_curve],
+            color_saturation_weights: vec![None],
+        })
+    }
+}
+
+impl ProcessingWindow {
+    fn parse(reader: &mut BitSliceReader) -> Result<Self> {
+        Ok(Self {
+            window_upper_left_corner_x: reader.get_n(16)?,
+            window_upper_left_corner_y: reader.get_n(16)?,
+            window_lower_right_corner_x: reader.get_n(16)?,
+            window_lower_right_corner_y: reader.get_n(16)?,
+            center_of_ellipse_x: reader.get_n(16)?,
+            center_of_ellipse_y: reader.get_n(16)?,
+            rotation_angle: reader.get_n(8)?,
+            semimajor_axis_internal_ellipse: reader.get_n(16)?,
+            semimajor_axis_external_ellipse: reader.get_n(16)?,
+            semiminor_axis_external_ellipse: reader.get_n(16)?,
+            overlap_process_option: reader.get()?,
+        })
+    }
+
+    fn write(&self, writer: &mut BitstreamIoWriter) -> Result<()> {
+        writer.write::<16, u16>(self.window_upper_left_corner_x)?;
+        writer.write::<16, u16>(self.window_upper_left_corner_y)?;
+        writer.write::<16, u16>(self.window_lower_right_corner_x)?;
+        writer.write::<16, u16>(self.window_lower_right_corner_y)?;
+        writer.write::<16, u16>(self.center_of_ellipse_x)?;
+        writer.write::<16, u16>(self.center_of_ellipse_y)?;
+        writer.write::<8, u8>(self.rotation_angle)?;
+        writer.write::<16, u16>(self.semimajor_axis_internal_ellipse)?;
+        writer.write::<16, u16>(self.semimajor_axis_external_ellipse)?;
+        writer.write::<16, u16>(self.semiminor_axis_external_ellipse)?;
+        writer.write_bit(self.overlap_process_option)?;
+
+        Ok(())
+    }
+}
+
+impl ActualPeakLuminance {
+    fn parse_if_present(reader: &mut BitSliceReader) -> Result<Option<Self>> {
+        let present_flag = reader.get()?;
+        if !present_flag {
+            return Ok(None);
+        }
+
+        let num_rows: u8 = reader.get_n(5)?;
+        let num_cols: u8 = reader.get_n(5)?;
+
+        let values = (0..num_rows as usize * num_cols as usize)
+            .map(|_| reader.get_n(4))
+            .collect::<Result<_>>()?;
+
+        Ok(Some(Self {
+            num_rows,
+            num_cols,
+            values,
+        }))
+    }
+
+    fn write_if_present(writer: &mut BitstreamIoWriter, value: Option<&Self>) -> Result<()> {
+        writer.write_bit(value.is_some())?;
+
+        if let Some(value) = value {
+            ensure!(
+                value.values.len() == value.num_rows as usize * value.num_cols as usize,
+                "Invalid actual peak luminance values count"
+            );
+
+            writer.write::<5, u8>(value.num_rows)?;
+            writer.write::<5, u8>(value.num_cols)?;
+
+            for v in &value.values {
+                writer.write::<4, u8>(*v)?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl LuminanceParameters {
+    fn parse(reader: &mut BitSliceReader) -> Result<Self> {
+        let maxscl = [reader.get_n(17)?, reader.get_n(17)?, reader.get_n(17)?];
+        let average_maxrgb = reader.get_n(17)?;
+
+        let num_distribution_maxrgb_percentiles: u8 = reader.get_n(4)?;
+        let distribution_maxrgb = (0..num_distribution_maxrgb_percentiles)
+            .map(|_| Ok((reader.get_n(7)?, reader.get_n(17)?)))
+            .collect::<Result<_>>()?;
+
+        let fraction_bright_pixels = reader.get_n(10)?;
+
+        Ok(Self {
+            maxscl,
+            average_maxrgb,
+            distribution_maxrgb,
+            fraction_bright_pixels,
+        })
+    }
+
+    fn write(&self, writer: &mut BitstreamIoWriter) -> Result<()> {
+        for v in self.maxscl {
+            writer.write::<17, u32>(v)?;
+        }
+        writer.write::<17, u32>(self.average_maxrgb)?;
+
+        writer.write::<4, u8>(self.distribution_maxrgb.len() as u8)?;
+        for (percentage, percentile) in &self.distribution_maxrgb {
+            writer.write::<7, u8>(*percentage)?;
+            writer.write::<17, u32>(*percentile)?;
+        }
+
+        writer.write::<10, u16>(self.fraction_bright_pixels)?;
+
+        Ok(())
+    }
+}
+
+impl BezierCurve {
+    fn parse(reader: &mut BitSliceReader) -> Result<Self> {
+        let knee_point_x = reader.get_n(12)?;
+        let knee_point_y = reader.get_n(12)?;
+
+        let num_bezier_curve_anchors: u8 = reader.get_n(4)?;
+        let bezier_curve_anchors = (0..num_bezier_curve_anchors)
+            .map(|_| reader.get_n(10))
+            .collect::<Result<_>>()?;
+
+        Ok(Self {
+            knee_point_x,
+            knee_point_y,
+            bezier_curve_anchors,
+        })
+    }
+
+    fn write(&self, writer: &mut BitstreamIoWriter) -> Result<()> {
+        writer.write::<12, u16>(self.knee_point_x)?;
+        writer.write::<12, u16>(self.knee_point_y)?;
+
+        writer.write::<4, u8>(self.bezier_curve_anchors.len() as u8)?;
+        for v in &self.bezier_curve_anchors {
+            writer.write::<10, u16>(*v)?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() -> Result<()> {
+        let data: &[u8] = &[
+            181, 0, 60, 0, 1, 4, 1, 64, 0, 12, 128, 60, 176, 25, 189, 13, 128, 0, 65, 228, 8, 0, 0,
+            40, 95, 0, 80, 1, 112, 200, 0, 5, 144, 0, 18, 88, 1, 174, 208, 11, 90, 248, 27, 227,
+            24, 91, 76, 0, 66, 144, 60, 36, 143, 74, 155, 249, 66, 219, 216, 55, 190, 87, 170, 0,
+        ];
+
+        let res = Hdr10PlusMetadata::parse(data)?;
+        assert_eq!(res.application_version, 1);
+        assert_eq!(res.num_windows, 1);
+        assert_eq!(res.targeted_system_display_maximum_luminance, 400);
+
+        let luminance_parameters = &res.luminance_parameters[0];
+        assert_eq!(luminance_parameters.maxscl, [7768, 6589, 6912]);
+        assert_eq!(luminance_parameters.average_maxrgb, 263);
+        assert_eq!(luminance_parameters.distribution_maxrgb.len(), 9);
+
+        let bezier_curve = res.bezier_curves[0].as_ref().unwrap();
+        assert_eq!(bezier_curve.knee_point_x, 164);
+        assert_eq!(bezier_curve.knee_point_y, 240);
+        assert_eq!(
+            bezier_curve.bezier_curve_anchors,
+            [143, 298, 447, 592, 731, 864, 891, 917, 938]
+        );
+
+        assert_eq!(res.max_content_light_level(), 776.8);
+        assert_eq!(res.frame_average_light_level(), 26.3);
+
+        // round trips back to original
+        let encoded = res.encode()?;
+        assert_eq!(data, &encoded);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_round_trip_all_fields() -> Result<()> {
+        // Every optional syntax element present, with the largest values of each field
+        let window = ProcessingWindow {
+            window_upper_left_corner_x: 0,
+            window_upper_left_corner_y: 1,
+            window_lower_right_corner_x: 3839,
+            window_lower_right_corner_y: u16::MAX,
+            center_of_ellipse_x: 1920,
+            center_of_ellipse_y: 1080,
+            rotation_angle: 180,
+            semimajor_axis_internal_ellipse: 100,
+            semimajor_axis_external_ellipse: 200,
+            semiminor_axis_external_ellipse: 150,
+            overlap_process_option: true,
+        };
+        let peak_luminance = ActualPeakLuminance {
+            num_rows: 2,
+            num_cols: 3,
+            values: vec![0, 1, 2, 13, 14, 15],
+        };
+        let luminance_parameters = LuminanceParameters {
+            maxscl: [0x1FFFF, 0, 1000],
+            average_maxrgb: 0x1FFFF,
+            distribution_maxrgb: (0..15).map(|i| (i * 7, i as u32 * 1000)).collect(),
+            fraction_bright_pixels: 1023,
+        };
+
+        let meta = Hdr10PlusMetadata {
+            application_version: 1,
+            num_windows: 3,
+            processing_windows: vec![window.clone(), window],
+            targeted_system_display_maximum_luminance: 0x7FFFFFF,
+            targeted_system_display_actual_peak_luminance: Some(peak_luminance.clone()),
+            luminance_parameters: vec![luminance_parameters; 3],
+            mastering_display_actual_peak_luminance: Some(peak_luminance),
+            bezier_curves: vec![
+                None,
+                Some(BezierCurve {
+                    knee_point_x: 4095,
+                    knee_point_y: 0,
+                    bezier_curve_anchors: vec![1023; 15],
+                }),
+                None,
+            ],
+            color_saturation_weights: vec![Some(63), None, Some(0)],
+        };
+
+        let encoded = meta.encode()?;
+        assert!(Hdr10PlusMetadata::is_hdr10plus(&encoded));
+        assert_eq!(Hdr10PlusMetadata::parse(&encoded)?, meta);
+
+        Ok(())
+    }
+}
@@ -0,0 +1,47 @@
+//! Lossless editing of the HDR metadata in HEVC bitstreams.
+//!
+//! ```no_run
+//! use hevc_hdr_editor::{EditConfig, Processor};
+//!
+//! # fn main() -> anyhow::Result<()> {
+//! let config = EditConfig::from_path("config.json")?;
+//! let input = std::fs::File::open("video.hevc")?;
+//!
+//! let mut processor = Processor::new(config, Vec::new())?;
+//! processor.edit_reader(input)?;
+//!
+//! let edited = processor.into_writer();
+//! # Ok(())
+//! # }
+//! ```
+
+mod atc_metadata;
+mod ave_metadata;
+mod ccv_metadata;
+mod cll_metadata;
+mod dovi_rpu;
+mod edit_config;
+mod hdr10plus_metadata;
+mod mdcv_metadata;
+mod pipeline;
+mod processor;
+mod progress;
+mod provenance;
+mod scanner;
+mod sps_vui;
+mod utils;
+
+pub use atc_metadata::AtcMetadata;
+pub use ave_metadata::AveMetadata;
+pub use ccv_metadata::{ContentColourVolume, ContentColourVolumePrimaries};
+pub use cll_metadata::CllMetadata;
+pub use edit_config::{
+    CllMatchCondition, CllSource, CllTransform, DoviL6Sync, EditAtcMetadata, EditAveMetadata,
+    EditCcvMetadata, EditCllMetadata, EditConfig, EditDoviConfig, EditHdr10PlusMetadata,
+    EditMdcvMetadata, EditProvenanceConfig, EditUduConfig, EditVuiMetadata, MdcvMatchCondition,
+    MdcvTransform, SeiRule, SeiRuleAction, SeiRulePayload, SeiUuid,
+};
+pub use hdr10plus_metadata::Hdr10PlusMetadata;
+pub use mdcv_metadata::{MasteringDisplayPrimaries, MdcvMetadata, MdcvPrimariesPreset};
+pub use processor::{MalformedNal, NalFraming, Processor};
+pub use progress::{ProgressCallback, ProgressEvent, ProgressPhase};
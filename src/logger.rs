@@ -0,0 +1,163 @@
+use std::io::Write;
+
+use anyhow::Result;
+use clap::ValueEnum;
+use log::kv::{self, Key, Value, VisitSource};
+use log::{LevelFilter, Log, Metadata, Record};
+use serde_json::{Map, Number};
+
+#[derive(ValueEnum, Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum LogFormat {
+    /// `LEVEL message key=value...`
+    #[default]
+    Text,
+    /// One JSON object per line, with the key-values as fields
+    Json,
+}
+
+/// Writes the logs of the editor to stderr
+pub struct Logger {
+    level: LevelFilter,
+    format: LogFormat,
+}
+
+impl Logger {
+    pub fn init(level: LevelFilter, format: LogFormat) -> Result<()> {
+        log::set_boxed_logger(Box::new(Self { level, format }))?;
+        log::set_max_level(level);
+
+        Ok(())
+    }
+
+    /// Level from the `-v` and `-q` counts, warnings by default
+    pub fn level(verbose: u8, quiet: u8) -> LevelFilter {
+        let level = (LevelFilter::Warn as usize + verbose as usize).saturating_sub(quiet as usize);
+
+        LevelFilter::iter().nth(level).unwrap_or(LevelFilter::max())
+    }
+
+    fn format_record(&self, record: &Record) -> String {
+        match self.format {
+            LogFormat::Text => {
+                let mut visitor = TextVisitor(format!("{:<5} {}", record.level(), record.args()));
+                let _ = record.key_values().visit(&mut visitor);
+
+                visitor.0
+            }
+            LogFormat::Json => {
+                let mut visitor = JsonVisitor(Map::new());
+                visitor.insert("level", record.level().as_str().into());
+                visitor.insert("message", record.args().to_string().into());
+                let _ = record.key_values().visit(&mut visitor);
+
+                serde_json::Value::Object(visitor.0).to_string()
+            }
+        }
+    }
+}
+
+impl Log for Logger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        // Only the editor's logs, not the dependencies'
+        metadata.level() <= self.level && metadata.target().starts_with(env!("CARGO_CRATE_NAME"))
+    }
+
+    fn log(&self, record: &Record) {
+        if self.enabled(record.metadata()) {
+            let _ = writeln!(std::io::stderr().lock(), "{}", self.format_record(record));
+        }
+    }
+
+    fn flush(&self) {
+        let _ = std::io::stderr().flush();
+    }
+}
+
+struct TextVisitor(String);
+
+impl<'kvs> VisitSource<'kvs> for TextVisitor {
+    fn visit_pair(&mut self, key: Key<'kvs>, value: Value<'kvs>) -> Result<(), kv::Error> {
+        self.0.push_str(&format!(" {key}={value}"));
+        Ok(())
+    }
+}
+
+struct JsonVisitor(Map<String, serde_json::Value>);
+
+impl JsonVisitor {
+    fn insert(&mut self, key: &str, value: serde_json::Value) {
+        self.0.insert(key.to_string(), value);
+    }
+}
+
+impl<'kvs> VisitSource<'kvs> for JsonVisitor {
+    fn visit_pair(&mut self, key: Key<'kvs>, value: Value<'kvs>) -> Result<(), kv::Error> {
+        let value = if let Some(v) = value.to_u64() {
+            v.into()
+        } else if let Some(v) = value.to_i64() {
+            v.into()
+        } else if let Some(v) = value.to_f64().and_then(Number::from_f64) {
+            v.into()
+        } else if let Some(v) = value.to_bool() {
+            v.into()
+        } else {
+            value.to_string().into()
+        };
+
+        self.insert(key.as_str(), value);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_format_record() {
+        let record = |logger: &Logger| {
+            logger.format_record(
+                &Record::builder()
+                    .level(log::Level::Warn)
+                    .args(format_args!("Passing through malformed SEI NAL"))
+                    .key_values(&[
+                        ("offset", Value::from(122_u64)),
+                        ("reason", Value::from("eof")),
+                    ])
+                    .build(),
+            )
+        };
+
+        let text = Logger {
+            level: LevelFilter::Warn,
+            format: LogFormat::Text,
+        };
+        assert_eq!(
+            record(&text),
+            "WARN  Passing through malformed SEI NAL offset=122 reason=eof"
+        );
+
+        let json = Logger {
+            level: LevelFilter::Warn,
+            format: LogFormat::Json,
+        };
+        assert_eq!(
+            serde_json::from_str::<serde_json::Value>(&record(&json)).unwrap(),
+            serde_json::json!({
+                "level": "WARN",
+                "message": "Passing through malformed SEI NAL",
+                "offset": 122,
+                "reason": "eof"
+            })
+        );
+    }
+
+    #[test]
+    fn test_level() {
+        assert_eq!(Logger::level(0, 0), LevelFilter::Warn);
+        assert_eq!(Logger::level(2, 0), LevelFilter::Debug);
+        assert_eq!(Logger::level(5, 0), LevelFilter::Trace);
+        assert_eq!(Logger::level(0, 1), LevelFilter::Error);
+        assert_eq!(Logger::level(0, 3), LevelFilter::Off);
+    }
+}
@@ -1,14 +1,20 @@
-use std::path::PathBuf;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
 
-use anyhow::Result;
-use clap::{Parser, ValueHint};
+use anyhow::{Result, bail};
+use clap::{ArgAction, Parser, ValueEnum, ValueHint};
 
-mod cll_metadata;
-mod edit_config;
-mod mdcv_metadata;
-mod processor;
-mod utils;
-use processor::Processor;
+use hevc_hdr_editor::{DoviL6Sync, EditConfig, Processor};
+
+mod batch;
+mod logger;
+mod output;
+use batch::{BatchManifest, BatchOptions, BatchReport, BatchStatus};
+use logger::{LogFormat, Logger};
+use output::{OutputFile, OutputTemplate};
 
 #[derive(Parser, Debug)]
 #[command(name = env!("CARGO_PKG_NAME"), about = "Utility to losslessly edit HDR metadata in HEVC files", author = "quietvoid", version = env!("CARGO_PKG_VERSION"))]
@@ -19,7 +25,7 @@ pub struct Opt {
         long,
         short = 'i',
         conflicts_with = "input_pos",
-        required_unless_present = "input_pos",
+        required_unless_present_any = ["input_pos", "batch"],
         value_hint = ValueHint::FilePath,
     )]
     pub input: Option<PathBuf>,
@@ -28,7 +34,7 @@ pub struct Opt {
         id = "input_pos",
         help = "Sets the input HEVC file to use, or piped with - (positional)",
         conflicts_with = "input",
-        required_unless_present = "input",
+        required_unless_present_any = ["input", "batch"],
         value_hint = ValueHint::FilePath
     )]
     pub input_pos: Option<PathBuf>,
@@ -36,20 +42,307 @@ pub struct Opt {
     #[arg(
         long,
         short = 'o',
-        help = "Sets the output JSON file to use",
+        help = "Sets the output HEVC file to use, or - for stdout",
         value_hint = ValueHint::FilePath
     )]
     pub output: Option<PathBuf>,
 
+    #[arg(
+        long,
+        help = "Sets the directory of the output files, named from the output template",
+        conflicts_with = "output",
+        value_hint = ValueHint::DirPath
+    )]
+    pub output_dir: Option<PathBuf>,
+
+    #[arg(
+        long,
+        help = "Sets the output file name template, {stem}, {name} and {ext} are replaced from the input. Defaults to {stem}_hdr_edited.hevc",
+        conflicts_with = "output"
+    )]
+    pub output_template: Option<String>,
+
     #[arg(
         long,
         short = 'c',
-        help = "Sets the edit JSON config file to use",
+        help = "Sets the edit JSON config file to use, optional with --batch",
+        required_unless_present = "batch",
+        value_hint = ValueHint::FilePath
+    )]
+    config: Option<PathBuf>,
+
+    #[arg(
+        long,
+        help = "Passes malformed SEI NAL units through unchanged instead of failing"
+    )]
+    tolerant: bool,
+
+    #[arg(
+        long,
+        help = "Splits, edits and writes on separate threads, which can be faster with several cores"
+    )]
+    pipeline: bool,
+
+    #[arg(
+        long,
+        short = 'v',
+        help = "Increases the log verbosity, -v for info, -vv for debug and -vvv for trace",
+        action = ArgAction::Count,
+        conflicts_with = "quiet"
+    )]
+    verbose: u8,
+
+    #[arg(
+        long,
+        short = 'q',
+        help = "Decreases the log verbosity and hides the progress bar and summary, -qq disables the logs",
+        action = ArgAction::Count
+    )]
+    quiet: u8,
+
+    #[arg(
+        long,
+        help = "Sets the format of the logs written to stderr",
+        value_enum,
+        default_value_t = LogFormat::Text
+    )]
+    log_format: LogFormat,
+
+    #[arg(
+        long,
+        help = "Sets how the progress is reported, json writes an event per second to stderr",
+        value_enum,
+        default_value_t = ProgressFormat::Bar,
+        conflicts_with = "batch"
+    )]
+    progress: ProgressFormat,
+
+    #[arg(
+        long,
+        help = "Edits the files of a JSON or CSV manifest, the config is used for entries without one",
+        conflicts_with_all = ["input", "input_pos", "output"],
         value_hint = ValueHint::FilePath
     )]
-    config: PathBuf,
+    batch: Option<PathBuf>,
+
+    #[arg(
+        long,
+        short = 'j',
+        help = "Number of files edited in parallel in batch mode",
+        default_value_t = 1,
+        requires = "batch"
+    )]
+    jobs: usize,
+
+    #[arg(
+        long,
+        help = "Keeps editing the remaining files after a failure in batch mode",
+        requires = "batch"
+    )]
+    continue_on_error: bool,
+
+    #[arg(
+        long,
+        help = "Sets the JSON file to write the batch report to",
+        requires = "batch",
+        value_hint = ValueHint::FilePath
+    )]
+    report: Option<PathBuf>,
+}
+
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+enum ProgressFormat {
+    /// Progress bar, hidden when writing to stdout or logging more than warnings
+    Bar,
+    /// Line delimited JSON events
+    Json,
+    None,
 }
+
+/// Interval of the JSON progress events
+const PROGRESS_EVENT_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Set on Ctrl-C or SIGTERM, the partial output is deleted
+static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+
 fn main() -> Result<()> {
     let opt = Opt::parse();
-    Processor::execute(opt)
+    Logger::init(Logger::level(opt.verbose, opt.quiet), opt.log_format)?;
+    handle_interrupts()?;
+
+    if let Some(manifest) = opt.batch.as_ref() {
+        return run_batch(manifest, &opt);
+    }
+
+    let output_template = output_template(&opt);
+    let input = input_from_either(opt.input.clone(), opt.input_pos.clone())?;
+    let output = match opt.output.clone() {
+        Some(output) => output,
+        None if output_template.is_set() => output_template.output_path(&input)?,
+        None => PathBuf::from("hdr_edited_output.hevc"),
+    };
+
+    let Some(config_path) = opt.config.as_ref() else {
+        bail!("No config file provided. See `hevc_hdr_editor --help`");
+    };
+    let config = EditConfig::from_path(config_path)?;
+
+    if output.as_os_str() == "-" {
+        edit(config, &input, std::io::stdout(), true, &opt)?;
+    } else {
+        // Only replaces the output once fully written
+        let writer = OutputFile::create(&input, &output)?;
+        edit(config, &input, writer, false, &opt)?.persist()?;
+    }
+
+    Ok(())
+}
+
+fn edit<W: Write + Send>(
+    config: EditConfig,
+    input: &Path,
+    writer: W,
+    to_stdout: bool,
+    opt: &Opt,
+) -> Result<W> {
+    // Nothing else can be written to stdout when it's the output
+    let writer = BufWriter::with_capacity(100_000, writer);
+    let mut processor = Processor::new(config, writer)?
+        .with_interrupt_flag(&INTERRUPTED)
+        .with_tolerant(opt.tolerant);
+
+    match opt.progress {
+        ProgressFormat::Bar => {
+            processor = processor.with_progress(!to_stdout && opt.verbose == 0 && opt.quiet == 0);
+        }
+        ProgressFormat::Json => {
+            processor = processor.with_progress_events(PROGRESS_EVENT_INTERVAL, |event| {
+                if let Ok(line) = serde_json::to_string(event) {
+                    eprintln!("{line}");
+                }
+            });
+        }
+        ProgressFormat::None => {}
+    }
+
+    if opt.pipeline {
+        processor.edit_file_pipelined(input)?;
+    } else {
+        processor.edit_file(input)?;
+    }
+
+    let print = |message: String| {
+        if opt.quiet > 0 {
+            return;
+        }
+
+        if to_stdout {
+            eprintln!("{message}");
+        } else {
+            println!("{message}");
+        }
+    };
+
+    if processor.config().remove_dovi() {
+        print(format!(
+            "Removed {} Dolby Vision NAL units",
+            processor.removed_dovi_nals()
+        ));
+    }
+    if processor.config().l6_sync() == Some(DoviL6Sync::FromHdr10) {
+        print(format!(
+            "Passed through {} unsynced Dolby Vision RPUs",
+            processor.unsynced_rpus()
+        ));
+    }
+    if opt.tolerant {
+        print(format!(
+            "Passed through {} malformed SEI NAL units",
+            processor.malformed_nals().len()
+        ));
+    }
+
+    Ok(processor
+        .into_writer()
+        .into_inner()
+        .map_err(|e| e.into_error())?)
+}
+
+fn run_batch(manifest: &Path, opt: &Opt) -> Result<()> {
+    let manifest = BatchManifest::from_path(manifest)?;
+    let options = BatchOptions {
+        config: opt.config.clone(),
+        output: output_template(opt),
+        jobs: opt.jobs,
+        continue_on_error: opt.continue_on_error,
+        interrupted: Some(&INTERRUPTED),
+        tolerant: opt.tolerant,
+    };
+
+    let report = manifest.run(&options);
+
+    if opt.quiet == 0 {
+        print_batch_report(&report);
+    }
+
+    if let Some(path) = opt.report.as_ref() {
+        serde_json::to_writer_pretty(File::create(path)?, &report)?;
+    }
+
+    if !report.is_success() {
+        bail!("{} of {} files failed", report.failed, report.entries.len());
+    }
+
+    Ok(())
+}
+
+fn print_batch_report(report: &BatchReport) {
+    for entry in &report.entries {
+        let status = match entry.status {
+            BatchStatus::Succeeded => "ok",
+            BatchStatus::Failed => "failed",
+            BatchStatus::Skipped => "skipped",
+        };
+
+        match (entry.error.as_ref(), entry.malformed_nals) {
+            (Some(error), _) => println!("{}: {status}, {error}", entry.input.display()),
+            (None, 0) => println!("{}: {status}", entry.input.display()),
+            (None, malformed) => println!(
+                "{}: {status}, {malformed} malformed SEI NAL units passed through",
+                entry.input.display()
+            ),
+        }
+    }
+
+    println!(
+        "{} files: {} succeeded, {} failed, {} skipped",
+        report.entries.len(),
+        report.succeeded,
+        report.failed,
+        report.skipped
+    );
+}
+
+fn output_template(opt: &Opt) -> OutputTemplate {
+    OutputTemplate {
+        dir: opt.output_dir.clone(),
+        template: opt.output_template.clone(),
+    }
+}
+
+/// The edit stops at the next chunk, unwinding to delete the temporary output
+fn handle_interrupts() -> Result<()> {
+    ctrlc::set_handler(|| INTERRUPTED.store(true, Ordering::Relaxed))?;
+
+    Ok(())
+}
+
+fn input_from_either(in1: Option<PathBuf>, in2: Option<PathBuf>) -> Result<PathBuf> {
+    match in1 {
+        Some(in1) => Ok(in1),
+        None => match in2 {
+            Some(in2) => Ok(in2),
+            None => bail!("No input file provided. See `hevc_hdr_editor --help`"),
+        },
+    }
 }
@@ -3,13 +3,16 @@ use serde::{Deserialize, Serialize};
 
 use bitvec_helpers::{bitslice_reader::BitSliceReader, bitstream_io_writer::BitstreamIoWriter};
 
-use super::edit_config::EditMdcvMetadata;
+use super::edit_config::{EditMdcvMetadata, MdcvMatchCondition, MdcvTransform};
 
 const D65_WHITEPOINT: [u16; 2] = [15635, 16450];
-const MDL_FACTOR: f32 = 10_000.0;
+pub const MDL_FACTOR: f32 = 10_000.0;
+
+/// Commonly used min luminance values, in units of 0.0001 nits
+const STANDARD_MIN_MDL_VALUES: [u32; 9] = [1, 5, 10, 20, 50, 100, 200, 500, 1000];
 
 // HEVC uses a g,b,r ordering, which we convert to a more natural r,g,b
-const COMPONENTS_MAPPING: [usize; 3] = [1, 2, 0];
+pub const COMPONENTS_MAPPING: [usize; 3] = [1, 2, 0];
 
 #[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
 pub struct MdcvMetadata {
@@ -100,15 +103,72 @@ impl MdcvMetadata {
         }
 
         if let Some(min_mdl) = src.min_display_mastering_luminance {
-            self.min_display_mastering_luminance = (min_mdl * MDL_FACTOR).round() as u32;
+            self.min_display_mastering_luminance = nits_to_mdl(min_mdl);
         }
 
         if let Some(max_mdl) = src.max_display_mastering_luminance {
-            self.max_display_mastering_luminance = (max_mdl * MDL_FACTOR).round() as u32;
+            self.max_display_mastering_luminance = nits_to_mdl(max_mdl);
         }
 
         self
     }
+
+    pub fn transform(mut self, transforms: &[MdcvTransform]) -> Self {
+        for transform in transforms {
+            match transform {
+                MdcvTransform::ScaleLuminance(factor) => {
+                    self.max_display_mastering_luminance =
+                        scale_mdl(self.max_display_mastering_luminance, *factor);
+                    self.min_display_mastering_luminance =
+                        scale_mdl(self.min_display_mastering_luminance, *factor);
+                }
+                MdcvTransform::RoundMinLuminance => {
+                    let min_mdl = self.min_display_mastering_luminance;
+
+                    if let Some(rounded) = STANDARD_MIN_MDL_VALUES
+                        .into_iter()
+                        .min_by_key(|v| v.abs_diff(min_mdl))
+                    {
+                        self.min_display_mastering_luminance = rounded;
+                    }
+                }
+            }
+        }
+
+        self
+    }
+
+    /// In nits
+    pub fn max_luminance(&self) -> f32 {
+        self.max_display_mastering_luminance as f32 / MDL_FACTOR
+    }
+
+    /// Whether the metadata should be edited, according to the edit's match conditions
+    pub fn should_edit(&self, src: &EditMdcvMetadata) -> bool {
+        src.match_condition
+            .as_ref()
+            .is_none_or(|condition| self.matches(condition))
+    }
+
+    pub fn matches(&self, condition: &MdcvMatchCondition) -> bool {
+        let preset_matches = condition
+            .preset
+            .as_ref()
+            .is_none_or(|preset| self.primaries == preset.primaries());
+        let primaries_matches = condition
+            .primaries
+            .as_ref()
+            .is_none_or(|primaries| &self.primaries == primaries);
+
+        let max_mdl_matches = condition
+            .max_display_mastering_luminance
+            .is_none_or(|max_mdl| self.max_display_mastering_luminance == nits_to_mdl(max_mdl));
+        let min_mdl_matches = condition
+            .min_display_mastering_luminance
+            .is_none_or(|min_mdl| self.min_display_mastering_luminance == nits_to_mdl(min_mdl));
+
+        preset_matches && primaries_matches && max_mdl_matches && min_mdl_matches
+    }
 }
 
 impl MasteringDisplayPrimaries {
@@ -147,6 +207,14 @@ impl MdcvPrimariesPreset {
     }
 }
 
+fn nits_to_mdl(nits: f32) -> u32 {
+    (nits * MDL_FACTOR).round() as u32
+}
+
+fn scale_mdl(mdl: u32, factor: f32) -> u32 {
+    (mdl as f64 * factor as f64).round() as u32
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -0,0 +1,205 @@
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+use anyhow::{Result, bail, ensure};
+use tempfile::NamedTempFile;
+
+pub const DEFAULT_OUTPUT_TEMPLATE: &str = "{stem}_hdr_edited.hevc";
+
+/// Output path derived from the input file
+#[derive(Debug, Clone, Default)]
+pub struct OutputTemplate {
+    /// Directory of the outputs, the input directory if not set
+    pub dir: Option<PathBuf>,
+
+    /// File name template, `{stem}`, `{name}` and `{ext}` are replaced from the input path.
+    /// Defaults to [`DEFAULT_OUTPUT_TEMPLATE`]
+    pub template: Option<String>,
+}
+
+impl OutputTemplate {
+    pub const fn is_set(&self) -> bool {
+        self.dir.is_some() || self.template.is_some()
+    }
+
+    pub fn output_path(&self, input: &Path) -> Result<PathBuf> {
+        ensure!(
+            input.as_os_str() != "-",
+            "Output templates require an input file, not stdin"
+        );
+
+        let template = self.template.as_deref().unwrap_or(DEFAULT_OUTPUT_TEMPLATE);
+        let file_name = render_template(template, input)?;
+
+        let dir = match self.dir.as_ref() {
+            Some(dir) => dir.as_path(),
+            None => input.parent().unwrap_or(Path::new("")),
+        };
+
+        Ok(dir.join(file_name))
+    }
+}
+
+fn render_template(template: &str, input: &Path) -> Result<String> {
+    let part = |value: Option<&std::ffi::OsStr>| value.map(|v| v.to_string_lossy().into_owned());
+
+    let mut rendered = String::with_capacity(template.len());
+    let mut rest = template;
+
+    while let Some(start) = rest.find('{') {
+        rendered.push_str(&rest[..start]);
+
+        let Some(len) = rest[start..].find('}') else {
+            bail!("Unterminated placeholder in output template {template}");
+        };
+        let key = &rest[start + 1..start + len];
+
+        let value = match key {
+            "stem" => part(input.file_stem()),
+            "name" => part(input.file_name()),
+            "ext" => part(input.extension()).or_else(|| Some(String::new())),
+            _ => bail!("Unknown placeholder {{{key}}} in output template {template}"),
+        };
+        let Some(value) = value else {
+            bail!("Input {} has no file name", input.display());
+        };
+
+        rendered.push_str(&value);
+        rest = &rest[start + len + 1..];
+    }
+    rendered.push_str(rest);
+
+    Ok(rendered)
+}
+
+/// Output written to a temporary file in the target directory, replacing the target on `persist`.
+/// The temporary file is deleted when dropped without being persisted.
+pub struct OutputFile {
+    file: NamedTempFile,
+    path: PathBuf,
+}
+
+impl OutputFile {
+    /// Creates the output directory if needed, refusing to overwrite the input
+    pub fn create(input: &Path, output: &Path) -> Result<Self> {
+        ensure_not_input(input, output)?;
+
+        let dir = match output.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+            Some(dir) => {
+                std::fs::create_dir_all(dir)?;
+                dir
+            }
+            None => Path::new("."),
+        };
+
+        let prefix = match output.file_name() {
+            Some(name) => format!(".{}.", name.to_string_lossy()),
+            None => bail!("Invalid output path {}", output.display()),
+        };
+
+        let mut builder = tempfile::Builder::new();
+        builder.prefix(&prefix).suffix(".tmp");
+
+        // Temporary files are private, the output gets the mode of a newly created file
+        #[cfg(unix)]
+        builder.permissions(std::os::unix::fs::PermissionsExt::from_mode(0o666));
+
+        let file = builder.tempfile_in(dir)?;
+
+        // Replacing a file keeps its mode
+        if let Ok(metadata) = std::fs::metadata(output) {
+            file.as_file().set_permissions(metadata.permissions())?;
+        }
+
+        Ok(Self {
+            file,
+            path: output.to_path_buf(),
+        })
+    }
+
+    /// Syncs the written data to disk, then renames the temporary file to the output path
+    pub fn persist(self) -> Result<()> {
+        self.file.as_file().sync_all()?;
+        self.file.persist(&self.path)?;
+
+        // Makes the rename durable
+        #[cfg(unix)]
+        {
+            let dir = match self.path.parent() {
+                Some(dir) if !dir.as_os_str().is_empty() => dir,
+                _ => Path::new("."),
+            };
+            std::fs::File::open(dir)?.sync_all()?;
+        }
+
+        Ok(())
+    }
+}
+
+impl Write for OutputFile {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.file.write(buf)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.file.flush()
+    }
+}
+
+/// The input would be truncated before being read
+pub fn ensure_not_input(input: &Path, output: &Path) -> Result<()> {
+    // stdin and stdout
+    if output.as_os_str() == "-" {
+        return Ok(());
+    }
+
+    let is_input = match (input.canonicalize(), output.canonicalize()) {
+        (Ok(input), Ok(output)) => input == output,
+        _ => input == output,
+    };
+    ensure!(
+        !is_input,
+        "Refusing to overwrite the input file {}",
+        input.display()
+    );
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_output_path() -> Result<()> {
+        let input = Path::new("season/S01E01.mkv");
+
+        let template = OutputTemplate::default();
+        assert_eq!(
+            template.output_path(input)?,
+            Path::new("season/S01E01_hdr_edited.hevc")
+        );
+
+        let template = OutputTemplate {
+            dir: Some(PathBuf::from("out")),
+            template: Some("{stem}.hdr10.{ext}.hevc".to_string()),
+        };
+        assert_eq!(
+            template.output_path(input)?,
+            Path::new("out/S01E01.hdr10.mkv.hevc")
+        );
+
+        let template = OutputTemplate {
+            dir: None,
+            template: Some("{name}_{missing}".to_string()),
+        };
+        assert!(template.output_path(input).is_err());
+        assert!(
+            OutputTemplate::default()
+                .output_path(Path::new("-"))
+                .is_err()
+        );
+
+        Ok(())
+    }
+}
@@ -0,0 +1,151 @@
+use std::io::{self, Write};
+use std::path::PathBuf;
+use std::sync::mpsc::{Receiver, SyncSender};
+
+use anyhow::{Result, anyhow};
+use hevc_parser::HevcParser;
+use hevc_parser::hevc::NALUnit;
+use hevc_parser::io::IoProcessor;
+use indicatif::ProgressBar;
+
+/// Chunks or buffers queued between two stages
+pub const PIPELINE_DEPTH: usize = 16;
+
+const WRITE_BUFFER_SIZE: usize = 1_000_000;
+
+/// NALs split from the input, with the chunk they index into
+#[derive(Default)]
+pub struct ParsedChunk {
+    pub nals: Vec<NALUnit>,
+    pub data: Vec<u8>,
+}
+
+/// Splitting stage, sending the NALs to the editing stage
+pub struct ChunkSender {
+    pub input: PathBuf,
+    pub progress_bar: ProgressBar,
+    pub chunks: SyncSender<ParsedChunk>,
+    /// Chunks edited, reused to avoid allocating
+    pub edited_chunks: Receiver<ParsedChunk>,
+}
+
+impl IoProcessor for ChunkSender {
+    fn input(&self) -> &PathBuf {
+        &self.input
+    }
+
+    fn update_progress(&mut self, delta: u64) {
+        self.progress_bar.inc(delta);
+    }
+
+    fn process_nals(&mut self, _parser: &HevcParser, nals: &[NALUnit], chunk: &[u8]) -> Result<()> {
+        let mut parsed = self.edited_chunks.try_recv().unwrap_or_default();
+        parsed.nals.clear();
+        parsed.nals.extend_from_slice(nals);
+        parsed.data.clear();
+        parsed.data.extend_from_slice(chunk);
+
+        // The editing stage stopped, its error is reported instead
+        self.chunks
+            .send(parsed)
+            .map_err(|_| anyhow!("Editing stopped"))
+    }
+
+    fn finalize(&mut self, _parser: &HevcParser) -> Result<()> {
+        Ok(())
+    }
+}
+
+/// Editing stage output, sending filled buffers to the writing stage
+pub struct ChunkWriter {
+    buffer: Vec<u8>,
+    buffers: Option<SyncSender<Vec<u8>>>,
+    /// Buffers written, reused to avoid allocating
+    written_buffers: Receiver<Vec<u8>>,
+}
+
+impl ChunkWriter {
+    pub fn new(buffers: SyncSender<Vec<u8>>, written_buffers: Receiver<Vec<u8>>) -> Self {
+        Self {
+            buffer: Vec::with_capacity(WRITE_BUFFER_SIZE),
+            buffers: Some(buffers),
+            written_buffers,
+        }
+    }
+
+    /// Ends the writing stage once the buffers are written
+    pub fn close(&mut self) {
+        self.buffers = None;
+    }
+
+    fn send_buffer(&mut self) -> io::Result<()> {
+        if self.buffer.is_empty() {
+            return Ok(());
+        }
+
+        let mut next = self
+            .written_buffers
+            .try_recv()
+            .unwrap_or_else(|_| Vec::with_capacity(WRITE_BUFFER_SIZE));
+        next.clear();
+
+        let buffer = std::mem::replace(&mut self.buffer, next);
+        let sent = self
+            .buffers
+            .as_ref()
+            .is_some_and(|buffers| buffers.send(buffer).is_ok());
+
+        if sent {
+            Ok(())
+        } else {
+            Err(io::Error::new(io::ErrorKind::BrokenPipe, "Writing stopped"))
+        }
+    }
+}
+
+impl Write for ChunkWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.buffer.extend_from_slice(buf);
+
+        if self.buffer.len() >= WRITE_BUFFER_SIZE {
+            self.send_buffer()?;
+        }
+
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.send_buffer()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::mpsc::sync_channel;
+
+    use super::*;
+
+    #[test]
+    fn test_chunk_writer() -> io::Result<()> {
+        let (tx, rx) = sync_channel(PIPELINE_DEPTH);
+        let (_written_tx, written_rx) = sync_channel(PIPELINE_DEPTH);
+        let mut writer = ChunkWriter::new(tx, written_rx);
+
+        writer.write_all(&[1; WRITE_BUFFER_SIZE + 1])?;
+        writer.write_all(&[2; 10])?;
+        writer.flush()?;
+        writer.close();
+
+        let written: Vec<u8> = rx.iter().flatten().collect();
+        assert_eq!(written.len(), WRITE_BUFFER_SIZE + 11);
+        assert_eq!(
+            written[WRITE_BUFFER_SIZE..],
+            [1, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2]
+        );
+
+        // The writing stage stopped
+        assert!(writer.write_all(&[3]).and_then(|_| writer.flush()).is_err());
+
+        Ok(())
+    }
+}
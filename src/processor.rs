@@ -1,103 +1,377 @@
-use std::fs::File;
-use std::io::{BufWriter, Write};
-use std::path::PathBuf;
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::sync_channel;
+use std::time::Duration;
 
-use anyhow::Result;
+use anyhow::{Result, bail, ensure};
 use hevc_parser::utils::clear_start_code_emulation_prevention_3_byte;
 use indicatif::ProgressBar;
 
 use hevc_parser::HevcParser;
-use hevc_parser::hevc::{NAL_SEI_PREFIX, NALUnit, SeiMessage};
+use hevc_parser::hevc::{
+    NAL_AUD, NAL_PPS, NAL_SEI_PREFIX, NAL_SPS, NAL_UNSPEC62, NAL_UNSPEC63, NAL_VPS, NALUnit,
+    SeiMessage,
+};
 use hevc_parser::io::processor::{HevcProcessor, HevcProcessorOpts};
 use hevc_parser::io::{IoFormat, IoProcessor, StartCodePreset};
 use num_enum::TryFromPrimitive;
 
-use crate::utils::encode_payload_to_sei_prefix;
+use crate::utils::{
+    consumed_chunk_len, encode_payload_to_sei_prefix, length_prefix_nals, split_buffer_nals,
+    stream_len,
+};
 
+use super::atc_metadata::AtcMetadata;
+use super::ave_metadata::AveMetadata;
+use super::ccv_metadata::ContentColourVolume;
 use super::cll_metadata::CllMetadata;
+use super::dovi_rpu::DoviRpu;
+use super::edit_config::EditConfig;
+use super::edit_config::{DoviL6Sync, SeiRuleAction};
+use super::hdr10plus_metadata::Hdr10PlusMetadata;
 use super::mdcv_metadata::MdcvMetadata;
+use super::pipeline::{ChunkSender, ChunkWriter, PIPELINE_DEPTH, ParsedChunk};
+use super::progress::{ProgressEvent, ProgressPhase, ProgressReporter};
+use super::provenance::{Provenance, ProvenanceRecord};
+use super::scanner::{MetadataScanner, ScanOptions};
+use super::sps_vui::edit_sps_vui;
 use super::utils::sei_message_data;
-use super::{Opt, edit_config::EditConfig};
 
-pub struct Processor {
+/// Edits the HDR metadata of a HEVC bitstream, writing the edited bitstream to `W`.
+/// A processor is meant to edit a single input.
+pub struct Processor<W: Write> {
     input: PathBuf,
     config: EditConfig,
 
     progress_bar: ProgressBar,
-    writer: BufWriter<File>,
+    show_progress: bool,
+    writer: W,
+
+    /// Stops the processing when set, usually from a signal handler
+    interrupted: Option<&'static AtomicBool>,
+
+    /// Encoded SEI NALs to insert in access units missing them, by payload type
+    inserted_seis: Vec<(u8, Vec<u8>)>,
+
+    /// Malformed SEI NALs are written unchanged instead of failing
+    tolerant: bool,
+
+    state: EditState,
+}
+
+/// State updated while editing, moved to the editing thread when pipelined
+#[derive(Default)]
+struct EditState {
+    progress_events: Option<ProgressReporter>,
+
+    /// Last MDCV metadata seen, after editing
+    last_mdcv: Option<MdcvMetadata>,
+    /// Last CLL metadata seen, after editing
+    last_cll: Option<CllMetadata>,
+    /// Edits requiring the MDCV were already skipped for missing it
+    warned_missing_mdcv: bool,
+
+    access_unit: AccessUnitState,
+    /// Index of the current access unit, in decode order
+    frame_index: usize,
+
+    /// HDR10+ metadata to inject, in decode order
+    injected_hdr10plus: Vec<Hdr10PlusMetadata>,
+
+    removed_dovi_nals: usize,
+    /// RPUs written unchanged when syncing the L6 metadata
+    unsynced_rpus: usize,
+    malformed_nals: Vec<MalformedNal>,
+    /// Input offset of the current chunk
+    stream_offset: u64,
+}
+
+/// SEI NAL unit that couldn't be parsed, passed through in tolerant mode
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MalformedNal {
+    /// Byte offset of the NAL header in the input stream.
+    /// For Matroska files, the offset within the concatenated frame data of the track,
+    /// which doesn't match a position in the file.
+    pub offset: u64,
+    pub reason: String,
+}
+
+/// State of the access unit being written
+#[derive(Debug, Default)]
+struct AccessUnitState {
+    has_sps: bool,
+    sei_payload_types: Vec<u8>,
+
+    /// The first slice was already written
+    in_slices: bool,
+
+    /// Provenance records read from the input
+    provenance: Option<Provenance>,
+}
+
+/// Framing of the NAL units in a buffer
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NalFraming {
+    /// Start code prefixed, the edited buffer uses 4 bytes start codes
+    AnnexB,
+    /// Prefixed by their size in big endian, using 1 to 4 bytes as in the `hvcC` box
+    LengthPrefixed(usize),
 }
 
 #[derive(TryFromPrimitive, Debug, Clone, PartialEq, Eq)]
 #[repr(u8)]
 enum SeiPayloadType {
+    RegisteredItuTT35 = 4,
+    UserDataUnregistered = 5,
     MasteringDisplayColourVolume = 137,
     ContentLightLevel = 144,
+    AlternativeTransferCharacteristics = 147,
+    AmbientViewingEnvironment = 148,
+    ContentColourVolume = 149,
 }
 
-pub enum EditedSei<'a> {
+enum EditedSei<'a> {
     None(&'a SeiMessage),
     Mdcv((&'a SeiMessage, MdcvMetadata)),
     Cll((&'a SeiMessage, CllMetadata)),
+    Atc((&'a SeiMessage, AtcMetadata)),
+    Ave((&'a SeiMessage, AveMetadata)),
+    Ccv((&'a SeiMessage, ContentColourVolume)),
+    Hdr10Plus((&'a SeiMessage, Hdr10PlusMetadata)),
+    /// Payload replaced by a SEI rule
+    Replaced((&'a SeiMessage, Vec<u8>)),
+    Removed,
 }
 
-impl Processor {
-    pub fn execute(opt: Opt) -> Result<()> {
-        let mut config = EditConfig::from_path(&opt.config)?;
+impl<W: Write> Processor<W> {
+    /// Validates the config, see [`EditConfig::setup`]
+    pub fn new(mut config: EditConfig, writer: W) -> Result<Self> {
         config.setup()?;
 
-        let Opt {
-            input,
-            input_pos,
-            output,
-            ..
-        } = opt;
+        let inserted_seis = Self::inserted_seis(&config)?;
+
+        Ok(Self {
+            input: PathBuf::new(),
+            config,
+            progress_bar: ProgressBar::hidden(),
+            show_progress: false,
+            writer,
+            interrupted: None,
+            inserted_seis,
+            tolerant: false,
+            state: EditState::default(),
+        })
+    }
+
+    /// Displays a progress bar when editing a file
+    pub fn with_progress(mut self, show_progress: bool) -> Self {
+        self.show_progress = show_progress;
+        self
+    }
+
+    /// Calls back with the scan and edit progress at most once per interval, and once each is done
+    pub fn with_progress_events<F>(mut self, interval: Duration, callback: F) -> Self
+    where
+        F: FnMut(&ProgressEvent) + Send + 'static,
+    {
+        self.state.progress_events = Some(ProgressReporter::new(Box::new(callback), interval));
+        self
+    }
 
-        let input = crate::utils::input_from_either(input, input_pos)?;
+    /// Stops the processing with an error once the flag is set
+    pub fn with_interrupt_flag(mut self, interrupted: &'static AtomicBool) -> Self {
+        self.interrupted = Some(interrupted);
+        self
+    }
 
-        let format = hevc_parser::io::format_from_path(&input)?;
+    /// Passes malformed SEI NALs through unchanged instead of failing, see [`Self::malformed_nals`]
+    pub fn with_tolerant(mut self, tolerant: bool) -> Self {
+        self.tolerant = tolerant;
+        self
+    }
 
-        let hevc_out = match output {
-            Some(path) => path,
-            None => PathBuf::from("hdr_edited_output.hevc"),
-        };
+    /// Edits a raw HEVC or Matroska file, or stdin with `-`.
+    /// The input is scanned first when the config requires it.
+    pub fn edit_file<P: AsRef<Path>>(&mut self, input: P) -> Result<()> {
+        let input = input.as_ref();
+        let format = self.prepare_file_edit(input)?;
 
-        let pb = crate::utils::initialize_progress_bar(&format, &input)?;
+        let file_path = (format != IoFormat::RawStdin).then_some(input);
+        Self::hevc_processor(format).process_file(self, file_path)?;
 
-        let mut processor = Self {
-            input,
-            config,
-            progress_bar: pb,
-            writer: BufWriter::with_capacity(
-                100_000,
-                File::create(&hevc_out).expect("Can't create file"),
-            ),
-        };
+        self.log_finished();
+
+        Ok(())
+    }
+
+    /// Edits a raw HEVC bitstream from any reader.
+    /// Configs requiring a first pass over the input are not supported.
+    pub fn edit_reader<R: Read>(&mut self, mut reader: R) -> Result<()> {
+        ensure!(
+            !self.config.requires_scan(),
+            "The config requires reading the input twice, only files are supported"
+        );
+
+        if let Some(progress) = self.state.progress_events.as_mut() {
+            progress.start(ProgressPhase::Edit, None);
+        }
+
+        Self::hevc_processor(IoFormat::RawStdin).process_io(&mut reader, self)
+    }
+
+    pub const fn config(&self) -> &EditConfig {
+        &self.config
+    }
 
-        processor.process_input(&format)
+    pub const fn removed_dovi_nals(&self) -> usize {
+        self.state.removed_dovi_nals
     }
 
-    pub fn process_input(&mut self, format: &IoFormat) -> Result<()> {
-        let chunk_size = 100_000;
+    /// Dolby Vision RPUs without L6 metadata or that couldn't be parsed,
+    /// passed through when syncing the L6 metadata
+    pub const fn unsynced_rpus(&self) -> usize {
+        self.state.unsynced_rpus
+    }
+
+    /// SEI NALs passed through in tolerant mode
+    pub fn malformed_nals(&self) -> &[MalformedNal] {
+        &self.state.malformed_nals
+    }
+
+    pub fn into_writer(self) -> W {
+        self.writer
+    }
+
+    fn check_interrupted(&self) -> Result<()> {
+        if self
+            .interrupted
+            .is_some_and(|flag| flag.load(Ordering::Relaxed))
+        {
+            bail!("Interrupted");
+        }
+
+        Ok(())
+    }
+
+    /// Scans the input if needed and sets up the progress, returning the input format
+    fn prepare_file_edit(&mut self, input: &Path) -> Result<IoFormat> {
+        let format = hevc_parser::io::format_from_path(input)?;
+        log::info!(input:% = input.display(), format:?; "Editing input");
+
+        self.scan_input(&format, input)?;
+
+        if self.show_progress {
+            self.progress_bar = crate::utils::initialize_progress_bar(&format, input)?;
+        }
+        self.input = input.to_path_buf();
+
+        if let Some(progress) = self.state.progress_events.as_mut() {
+            progress.start(ProgressPhase::Edit, stream_len(&format, input)?);
+        }
+
+        Ok(format)
+    }
+
+    fn log_finished(&self) {
+        log::info!(
+            input:% = self.input.display(),
+            access_units = self.state.frame_index + 1,
+            malformed_nals = self.state.malformed_nals.len(),
+            removed_dovi_nals = self.state.removed_dovi_nals,
+            unsynced_rpus = self.state.unsynced_rpus;
+            "Finished editing"
+        );
+    }
 
+    /// Processor with the same settings writing to `writer`, taking over the edit state.
+    /// The caller moves the state back once done.
+    fn with_writer<V: Write>(&mut self, writer: V) -> Processor<V> {
+        Processor {
+            input: self.input.clone(),
+            config: self.config.clone(),
+            progress_bar: self.progress_bar.clone(),
+            show_progress: self.show_progress,
+            writer,
+            interrupted: self.interrupted,
+            inserted_seis: self.inserted_seis.clone(),
+            tolerant: self.tolerant,
+            state: std::mem::take(&mut self.state),
+        }
+    }
+
+    fn hevc_processor(format: IoFormat) -> HevcProcessor {
         let processor_opts = HevcProcessorOpts {
             parse_nals: false,
             ..Default::default()
         };
-        let mut processor = HevcProcessor::new(format.clone(), processor_opts, chunk_size);
 
-        let file_path = if let IoFormat::RawStdin = format {
-            None
-        } else {
-            Some(self.input.clone())
+        HevcProcessor::new(format, processor_opts, 100_000)
+    }
+
+    /// First pass over the input, for the values computed from the stream
+    fn scan_input(&mut self, format: &IoFormat, input: &Path) -> Result<()> {
+        let mut options = ScanOptions {
+            interrupted: self.interrupted,
+            show_progress: self.show_progress,
+            progress_events: self.state.progress_events.as_mut(),
         };
 
-        processor.process_file(self, file_path)
+        if let Some(new_cll) = self.config.cll.as_mut()
+            && let Some(source) = new_cll.source
+        {
+            let computed = MetadataScanner::scan_light_level(format, input, source, &mut options)?;
+
+            new_cll.max_content_light_level = Some(computed.max_content_light_level);
+            new_cll.max_frame_average_light_level = Some(computed.max_frame_average_light_level);
+        }
+
+        if self.config.l6_sync() == Some(DoviL6Sync::ToHdr10) {
+            let level6 = MetadataScanner::scan_level6(format, input, &mut options)?;
+            self.config.copy_level6(&level6);
+        }
+
+        if let Some(json_path) = self
+            .config
+            .hdr10plus
+            .as_ref()
+            .and_then(|e| e.inject.as_ref())
+        {
+            self.state.injected_hdr10plus =
+                Self::injected_hdr10plus(format, input, json_path, &mut options)?;
+        }
+
+        Ok(())
     }
 
     fn get_edited_sei_for_message<'a>(
         sei_payload: &[u8],
         msg: &'a SeiMessage,
         config: &EditConfig,
+        mdcv: Option<&MdcvMetadata>,
+    ) -> Result<EditedSei<'a>> {
+        let replaced_data = match config.sei_rule_action(msg.payload_type) {
+            Some(SeiRuleAction::Keep) => return Ok(EditedSei::None(msg)),
+            Some(SeiRuleAction::Drop) => return Ok(EditedSei::Removed),
+            Some(SeiRuleAction::Replace(payload)) => Some(payload.data()),
+            _ => None,
+        };
+
+        // Typed editing is applied on top of the replaced payload
+        let data = replaced_data.unwrap_or_else(|| sei_message_data(msg, sei_payload));
+        let edited_sei = Self::get_typed_edited_sei(data, msg, config, mdcv)?;
+
+        Ok(match (edited_sei, replaced_data) {
+            (EditedSei::None(msg), Some(data)) => EditedSei::Replaced((msg, data.to_vec())),
+            (edited_sei, _) => edited_sei,
+        })
+    }
+
+    fn get_typed_edited_sei<'a>(
+        data: &[u8],
+        msg: &'a SeiMessage,
+        config: &EditConfig,
+        mdcv: Option<&MdcvMetadata>,
     ) -> Result<EditedSei<'a>> {
         // leave original sei untouched by default
         let mut ret = Ok(EditedSei::None(msg));
@@ -108,28 +382,761 @@ impl Processor {
         }
 
         let payload_type = payload_type.unwrap();
-        let data = sei_message_data(msg, sei_payload);
 
         match payload_type {
+            SeiPayloadType::RegisteredItuTT35 => {
+                // Other T.35 payloads are left untouched
+                if let Some(new_hdr10plus) = config.hdr10plus.as_ref()
+                    && Hdr10PlusMetadata::is_hdr10plus(data)
+                {
+                    // Injected metadata replaces the existing one
+                    ret = if new_hdr10plus.remove || new_hdr10plus.inject.is_some() {
+                        Ok(EditedSei::Removed)
+                    } else {
+                        Hdr10PlusMetadata::parse(data)
+                            .map(|meta| EditedSei::Hdr10Plus((msg, meta.copy(new_hdr10plus, mdcv))))
+                    };
+                }
+            }
+            SeiPayloadType::UserDataUnregistered => {
+                // Previous records are replaced or consumed
+                let is_provenance = config.edits_provenance() && Provenance::is_provenance(data);
+
+                if is_provenance
+                    || config
+                        .udu
+                        .as_ref()
+                        .is_some_and(|udu| udu.should_remove(data))
+                {
+                    ret = Ok(EditedSei::Removed);
+                }
+            }
             SeiPayloadType::MasteringDisplayColourVolume => {
                 if let Some(new_mdcv) = config.mdcv.as_ref() {
-                    ret = MdcvMetadata::parse(data)
-                        .map(|meta| EditedSei::Mdcv((msg, meta.copy(new_mdcv))));
+                    ret = MdcvMetadata::parse(data).map(|meta| {
+                        if meta.should_edit(new_mdcv) {
+                            let meta = meta.copy(new_mdcv).transform(&new_mdcv.transforms);
+                            EditedSei::Mdcv((msg, meta))
+                        } else {
+                            EditedSei::None(msg)
+                        }
+                    });
                 }
             }
             SeiPayloadType::ContentLightLevel => {
                 if let Some(new_cll) = config.cll.as_ref() {
-                    ret = CllMetadata::parse(data)
-                        .map(|meta| EditedSei::Cll((msg, meta.copy(new_cll))));
+                    ret = CllMetadata::parse(data).map(|meta| {
+                        if meta.should_edit(new_cll) {
+                            let meta = meta.copy(new_cll).transform(&new_cll.transforms, mdcv);
+                            EditedSei::Cll((msg, meta))
+                        } else {
+                            EditedSei::None(msg)
+                        }
+                    });
+                }
+            }
+            SeiPayloadType::AlternativeTransferCharacteristics => {
+                if let Some(new_atc) = config.atc.as_ref() {
+                    ret = if new_atc.remove {
+                        Ok(EditedSei::Removed)
+                    } else {
+                        AtcMetadata::parse(data)
+                            .map(|meta| EditedSei::Atc((msg, meta.copy(new_atc))))
+                    };
+                }
+            }
+            SeiPayloadType::AmbientViewingEnvironment => {
+                if let Some(new_ave) = config.ave.as_ref() {
+                    ret = if new_ave.remove {
+                        Ok(EditedSei::Removed)
+                    } else {
+                        AveMetadata::parse(data)
+                            .map(|meta| EditedSei::Ave((msg, meta.copy(new_ave))))
+                    };
+                }
+            }
+            SeiPayloadType::ContentColourVolume => {
+                if let Some(new_ccv) = config.ccv.as_ref() {
+                    ret = if new_ccv.remove {
+                        Ok(EditedSei::Removed)
+                    } else {
+                        ContentColourVolume::parse(data)
+                            .map(|meta| EditedSei::Ccv((msg, meta.copy(new_ccv))))
+                    };
                 }
             }
         };
 
         ret
     }
+
+    /// MDCV metadata of the current access unit, after editing.
+    /// Falls back to the last seen MDCV when the access unit doesn't have any,
+    /// or when its MDCV is after the end of the chunk.
+    ///
+    /// `nals` is expected to start from the current NAL.
+    fn access_unit_mdcv(&mut self, nals: &[NALUnit], chunk: &[u8]) -> Result<Option<MdcvMetadata>> {
+        let lookahead = nals.iter().position(NALUnit::is_slice);
+        let sei_nals = nals[..lookahead.unwrap_or(nals.len())]
+            .iter()
+            .filter(|nal| nal.nal_type == NAL_SEI_PREFIX);
+
+        // Unparseable NALs are skipped, they fail or are passed through once reached
+        for nal in sei_nals {
+            let sei_payload =
+                clear_start_code_emulation_prevention_3_byte(&chunk[nal.start..nal.end]);
+            let Ok(messages) = SeiMessage::parse_sei_rbsp(&sei_payload) else {
+                continue;
+            };
+
+            let mdcv_msg = messages
+                .iter()
+                .find(|msg| msg.payload_type == SeiPayloadType::MasteringDisplayColourVolume as u8);
+
+            if let Some(msg) = mdcv_msg {
+                let data = match self.config.sei_rule_action(msg.payload_type) {
+                    Some(SeiRuleAction::Drop) => continue,
+                    Some(SeiRuleAction::Replace(payload)) => payload.data(),
+                    _ => sei_message_data(msg, &sei_payload),
+                };
+                let Ok(mut mdcv) = MdcvMetadata::parse(data) else {
+                    continue;
+                };
+
+                if let Some(new_mdcv) = self.config.mdcv.as_ref().filter(|e| mdcv.should_edit(e)) {
+                    mdcv = mdcv.copy(new_mdcv).transform(&new_mdcv.transforms);
+                }
+
+                self.state.last_mdcv = Some(mdcv);
+                return Ok(self.state.last_mdcv.clone());
+            }
+        }
+
+        let offset = self.nal_offset(&nals[0]);
+        let access_unit = self.state.frame_index;
+
+        if self.state.last_mdcv.is_none() {
+            if !self.state.warned_missing_mdcv {
+                self.state.warned_missing_mdcv = true;
+                log::warn!(
+                    offset,
+                    access_unit;
+                    "No MDCV metadata, the edits requiring it are skipped"
+                );
+            }
+        } else if lookahead.is_none() {
+            // The rest of the access unit is in the next chunk
+            log::debug!(offset, access_unit; "Using the previous MDCV metadata");
+        }
+
+        Ok(self.state.last_mdcv.clone())
+    }
+
+    /// Keeps track of the MDCV/CLL metadata being written
+    fn update_last_metadata(&mut self, sei_payload: &[u8], edited_seis: &[EditedSei]) {
+        for edited_sei in edited_seis {
+            match edited_sei {
+                EditedSei::Mdcv((_, meta)) => self.state.last_mdcv = Some(meta.clone()),
+                EditedSei::Cll((_, meta)) => self.state.last_cll = Some(meta.clone()),
+                EditedSei::Atc(_)
+                | EditedSei::Ave(_)
+                | EditedSei::Ccv(_)
+                | EditedSei::Hdr10Plus(_)
+                | EditedSei::Removed => {}
+                EditedSei::None(msg) => {
+                    self.update_last_metadata_from_payload(
+                        msg.payload_type,
+                        sei_message_data(msg, sei_payload),
+                    );
+                }
+                EditedSei::Replaced((msg, data)) => {
+                    self.update_last_metadata_from_payload(msg.payload_type, data);
+                }
+            }
+        }
+    }
+
+    /// Edited SEI NALs replacing the first NAL with the number of edited messages,
+    /// `None` to write it unchanged.
+    /// The following NALs of the chunk are used to find the access unit MDCV.
+    fn edited_sei_nals(
+        &mut self,
+        nals: &[NALUnit],
+        chunk: &[u8],
+    ) -> Result<Option<(Vec<Vec<u8>>, u64)>> {
+        let nal_data = &chunk[nals[0].start..nals[0].end];
+        let offset = self.nal_offset(&nals[0]);
+        let access_unit = self.state.frame_index;
+
+        let sei_payload = clear_start_code_emulation_prevention_3_byte(nal_data);
+        let messages = SeiMessage::parse_sei_rbsp(&sei_payload)?;
+
+        log::debug!(
+            offset,
+            access_unit,
+            payload_types:? = messages.iter().map(|msg| msg.payload_type).collect::<Vec<_>>();
+            "Parsed SEI NAL"
+        );
+
+        let has_editable_message = messages.iter().any(|e| {
+            let has_rule = self
+                .config
+                .sei_rule_action(e.payload_type)
+                .is_some_and(|action| !matches!(action, SeiRuleAction::Keep));
+
+            has_rule
+                || match SeiPayloadType::try_from(e.payload_type) {
+                    // Only HDR10+ can be edited, leave other T.35 payloads as is
+                    Ok(SeiPayloadType::RegisteredItuTT35) => self.config.hdr10plus.is_some(),
+                    Ok(SeiPayloadType::UserDataUnregistered) => {
+                        self.config.udu.is_some() || self.config.edits_provenance()
+                    }
+                    // Provenance records are carried over from the untouched metadata
+                    Ok(SeiPayloadType::MasteringDisplayColourVolume) => {
+                        self.config.mdcv.is_some() || self.config.edits_provenance()
+                    }
+                    Ok(SeiPayloadType::ContentLightLevel) => {
+                        self.config.cll.is_some() || self.config.edits_provenance()
+                    }
+                    Ok(SeiPayloadType::AlternativeTransferCharacteristics) => {
+                        self.config.atc.is_some()
+                    }
+                    Ok(SeiPayloadType::AmbientViewingEnvironment) => self.config.ave.is_some(),
+                    Ok(SeiPayloadType::ContentColourVolume) => self.config.ccv.is_some(),
+                    Err(_) => false,
+                }
+        });
+
+        if !has_editable_message {
+            for msg in &messages {
+                self.update_last_metadata_from_payload(
+                    msg.payload_type,
+                    sei_message_data(msg, &sei_payload),
+                );
+            }
+            self.state
+                .access_unit
+                .sei_payload_types
+                .extend(messages.iter().map(|msg| msg.payload_type));
+
+            return Ok(None);
+        }
+
+        let mdcv = if self.config.requires_mdcv() {
+            self.access_unit_mdcv(nals, chunk)?
+        } else {
+            None
+        };
+
+        let mut edited_seis = messages
+            .iter()
+            .map(|msg| {
+                Self::get_edited_sei_for_message(&sei_payload, msg, &self.config, mdcv.as_ref())
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        let provenance = if self.config.edits_provenance() {
+            self.apply_provenance(&sei_payload, &messages, &mut edited_seis)?
+        } else {
+            None
+        };
+
+        // Written before the edited messages, for the revert to be read first
+        let provenance_nal = provenance
+            .filter(|_| self.config.insert_provenance())
+            .map(|provenance| {
+                encode_payload_to_sei_prefix(
+                    SeiPayloadType::UserDataUnregistered as u8,
+                    &provenance.encode()?,
+                )
+            })
+            .transpose()?;
+
+        // Split all messages into separate NALs, even if they're not edited
+        let new_nals = provenance_nal
+            .into_iter()
+            .map(Ok)
+            .chain(
+                edited_seis
+                    .iter()
+                    .filter(|edited_sei| !matches!(edited_sei, EditedSei::Removed))
+                    .map(|edited_sei| edited_sei.encode_to_nal(&sei_payload)),
+            )
+            .collect::<Result<Vec<_>>>()?;
+
+        let mut edited_messages = 0;
+        for (msg, edited_sei) in messages.iter().zip(&edited_seis) {
+            if let Some(action) = edited_sei.action() {
+                edited_messages += 1;
+                log::debug!(
+                    offset,
+                    access_unit,
+                    payload_type = msg.payload_type,
+                    action;
+                    "Edited SEI message"
+                );
+            }
+        }
+        if messages.len() > 1 {
+            log::debug!(offset, access_unit, nals = new_nals.len(); "Split SEI NAL");
+        }
+
+        // Only updated once the NAL is known to be valid
+        self.update_last_metadata(&sei_payload, &edited_seis);
+        self.state
+            .access_unit
+            .sei_payload_types
+            .extend(messages.iter().map(|msg| msg.payload_type));
+
+        Ok(Some((new_nals, edited_messages)))
+    }
+
+    fn add_malformed_nal(&mut self, nal: &NALUnit, error: anyhow::Error) {
+        let malformed = MalformedNal {
+            offset: self.nal_offset(nal),
+            reason: format!("{error:#}"),
+        };
+
+        log::warn!(
+            offset = malformed.offset,
+            access_unit = self.state.frame_index,
+            reason:% = malformed.reason;
+            "Passing through malformed SEI NAL"
+        );
+        self.state.malformed_nals.push(malformed);
+    }
+
+    fn count_edited_seis(&mut self, count: u64) {
+        if let Some(progress) = self.state.progress_events.as_mut() {
+            progress.edited_seis += count;
+        }
+    }
+
+    /// Offset of the NAL header in the input stream
+    fn nal_offset(&self, nal: &NALUnit) -> u64 {
+        self.state.stream_offset + nal.start as u64
+    }
+
+    /// Offset of the next chunk in the input stream
+    fn update_stream_offset(&mut self, nals: &[NALUnit], chunk: &[u8]) {
+        self.state.stream_offset += consumed_chunk_len(nals, chunk) as u64;
+    }
+
+    /// Records the MDCV/CLL edits, or restores the original metadata when reverting
+    fn apply_provenance<'a>(
+        &mut self,
+        sei_payload: &[u8],
+        messages: &'a [SeiMessage],
+        edited_seis: &mut [EditedSei<'a>],
+    ) -> Result<Option<Provenance>> {
+        let udu_payload_type = SeiPayloadType::UserDataUnregistered as u8;
+
+        for msg in messages
+            .iter()
+            .filter(|msg| msg.payload_type == udu_payload_type)
+        {
+            if let Some(provenance) = Provenance::parse(sei_message_data(msg, sei_payload))? {
+                match self.state.access_unit.provenance.as_mut() {
+                    Some(previous) => previous.merge(provenance),
+                    None => self.state.access_unit.provenance = Some(provenance),
+                }
+            }
+        }
+
+        let revert = self.config.revert_provenance();
+        let previous = self.state.access_unit.provenance.as_ref();
+        let mut provenance = Provenance::new();
+
+        for (msg, edited_sei) in messages.iter().zip(edited_seis.iter_mut()) {
+            let Some(output) = edited_sei.output_payload(sei_payload)? else {
+                continue;
+            };
+            let input = sei_message_data(msg, sei_payload);
+
+            match SeiPayloadType::try_from(msg.payload_type) {
+                Ok(SeiPayloadType::MasteringDisplayColourVolume) => {
+                    // Unparseable metadata is not recorded nor reverted
+                    let previous = previous.and_then(|p| p.mdcv.as_ref());
+                    let Ok(output) = MdcvMetadata::parse(&output) else {
+                        continue;
+                    };
+
+                    if !revert {
+                        let Ok(input) = MdcvMetadata::parse(input) else {
+                            continue;
+                        };
+                        provenance.mdcv = ProvenanceRecord::new(previous, &input, &output);
+                    } else if let Some(original) = previous.and_then(|p| p.revert(&output)) {
+                        *edited_sei = EditedSei::Mdcv((msg, original.clone()));
+                    }
+                }
+                Ok(SeiPayloadType::ContentLightLevel) => {
+                    let previous = previous.and_then(|p| p.cll.as_ref());
+                    let Ok(output) = CllMetadata::parse(&output) else {
+                        continue;
+                    };
+
+                    if !revert {
+                        let Ok(input) = CllMetadata::parse(input) else {
+                            continue;
+                        };
+                        provenance.cll = ProvenanceRecord::new(previous, &input, &output);
+                    } else if let Some(original) = previous.and_then(|p| p.revert(&output)) {
+                        *edited_sei = EditedSei::Cll((msg, original.clone()));
+                    }
+                }
+                _ => (),
+            }
+        }
+
+        Ok((!provenance.is_empty()).then_some(provenance))
+    }
+
+    /// Untouched metadata is only parsed when used by the edits, and unknown when unparseable
+    fn update_last_metadata_from_payload(&mut self, payload_type: u8, data: &[u8]) {
+        if !self.config.tracks_hdr10_metadata() {
+            return;
+        }
+
+        match SeiPayloadType::try_from(payload_type) {
+            Ok(SeiPayloadType::MasteringDisplayColourVolume) => {
+                self.state.last_mdcv = MdcvMetadata::parse(data).ok();
+            }
+            Ok(SeiPayloadType::ContentLightLevel) => {
+                self.state.last_cll = CllMetadata::parse(data).ok();
+            }
+            _ => (),
+        }
+    }
+
+    /// SEI messages to insert when enabled in the config
+    fn inserted_seis(config: &EditConfig) -> Result<Vec<(u8, Vec<u8>)>> {
+        let mut seis = Vec::new();
+
+        for rule in &config.sei_rules {
+            if let SeiRuleAction::Insert(payload) = &rule.action {
+                seis.push((
+                    rule.payload_type,
+                    encode_payload_to_sei_prefix(rule.payload_type, payload.data())?,
+                ));
+            }
+        }
+
+        if let Some(meta) = config
+            .atc
+            .as_ref()
+            .filter(|atc| atc.insert)
+            .and_then(AtcMetadata::from_edit)
+        {
+            let payload_type = SeiPayloadType::AlternativeTransferCharacteristics as u8;
+            seis.push((
+                payload_type,
+                encode_payload_to_sei_prefix(payload_type, &meta.encode()?)?,
+            ));
+        }
+
+        if let Some(meta) = config
+            .ave
+            .as_ref()
+            .filter(|ave| ave.insert)
+            .and_then(AveMetadata::from_edit)
+        {
+            let payload_type = SeiPayloadType::AmbientViewingEnvironment as u8;
+            seis.push((
+                payload_type,
+                encode_payload_to_sei_prefix(payload_type, &meta.encode()?)?,
+            ));
+        }
+
+        if let Some(meta) = config
+            .ccv
+            .as_ref()
+            .filter(|ccv| ccv.insert)
+            .map(ContentColourVolume::from_edit)
+        {
+            let payload_type = SeiPayloadType::ContentColourVolume as u8;
+            seis.push((
+                payload_type,
+                encode_payload_to_sei_prefix(payload_type, &meta.encode()?)?,
+            ));
+        }
+
+        Ok(seis)
+    }
+
+    /// Tracks the access unit boundaries, inserting the missing SEI messages before the first slice
+    fn update_access_unit(&mut self, nal: &NALUnit, nal_data: &[u8]) -> Result<()> {
+        if nal.is_slice() {
+            // first_slice_segment_in_pic_flag, right after the NAL header
+            let first_slice_in_pic = nal_data.get(2).is_some_and(|b| b & 0x80 != 0);
+
+            // Next access unit without any non-VCL NAL in between
+            if self.state.access_unit.in_slices && first_slice_in_pic {
+                self.state.access_unit = AccessUnitState::default();
+                self.state.frame_index += 1;
+            }
+
+            if !self.state.access_unit.in_slices {
+                self.write_inserted_seis()?;
+                self.write_injected_hdr10plus()?;
+
+                self.state.access_unit.in_slices = true;
+            }
+        } else {
+            let starts_access_unit = matches!(
+                nal.nal_type,
+                NAL_VPS | NAL_SPS | NAL_PPS | NAL_AUD | NAL_SEI_PREFIX
+            );
+
+            if self.state.access_unit.in_slices && starts_access_unit {
+                self.state.access_unit = AccessUnitState::default();
+                self.state.frame_index += 1;
+            }
+
+            if nal.nal_type == NAL_SPS {
+                self.state.access_unit.has_sps = true;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Only access units with a SPS get the inserted messages
+    fn write_inserted_seis(&mut self) -> Result<()> {
+        if !self.state.access_unit.has_sps {
+            return Ok(());
+        }
+
+        let mut inserted = 0;
+        for (payload_type, data) in &self.inserted_seis {
+            if !self
+                .state
+                .access_unit
+                .sei_payload_types
+                .contains(payload_type)
+            {
+                log::debug!(
+                    access_unit = self.state.frame_index,
+                    payload_type = *payload_type;
+                    "Inserted SEI message"
+                );
+
+                NALUnit::write_with_preset(
+                    &mut self.writer,
+                    data,
+                    StartCodePreset::Four,
+                    NAL_SEI_PREFIX,
+                    false,
+                )?;
+                inserted += 1;
+            }
+        }
+        self.count_edited_seis(inserted);
+
+        Ok(())
+    }
+
+    /// HDR10+ metadata from the JSON file, reordered into decode order
+    fn injected_hdr10plus(
+        format: &IoFormat,
+        input: &Path,
+        json_path: &Path,
+        options: &mut ScanOptions,
+    ) -> Result<Vec<Hdr10PlusMetadata>> {
+        let metadata = Hdr10PlusMetadata::from_json_path(json_path)?;
+        let presentation_order = MetadataScanner::scan_frame_order(format, input, options)?;
+
+        ensure!(
+            metadata.len() == presentation_order.len(),
+            "HDR10+ JSON metadata has {} frames, but the input has {} frames",
+            metadata.len(),
+            presentation_order.len()
+        );
+
+        Ok(presentation_order
+            .into_iter()
+            .map(|presentation_index| metadata[presentation_index].clone())
+            .collect())
+    }
+
+    /// Writes the injected HDR10+ metadata of the current frame, with the config edits applied
+    fn write_injected_hdr10plus(&mut self) -> Result<()> {
+        let (Some(meta), Some(edit)) = (
+            self.state.injected_hdr10plus.get(self.state.frame_index),
+            self.config.hdr10plus.as_ref(),
+        ) else {
+            return Ok(());
+        };
+
+        log::debug!(access_unit = self.state.frame_index; "Injected HDR10+ metadata");
+
+        let meta = meta.clone().copy(edit, self.state.last_mdcv.as_ref());
+        let data =
+            encode_payload_to_sei_prefix(SeiPayloadType::RegisteredItuTT35 as u8, &meta.encode()?)?;
+
+        NALUnit::write_with_preset(
+            &mut self.writer,
+            &data,
+            StartCodePreset::Four,
+            NAL_SEI_PREFIX,
+            false,
+        )?;
+        self.count_edited_seis(1);
+
+        Ok(())
+    }
+
+    /// Rewrites the RPU L6 metadata from the last written MDCV/CLL metadata.
+    /// RPUs that can't be parsed are written unchanged.
+    fn write_synced_rpu(&mut self, nal: &NALUnit, nal_data: &[u8]) -> Result<()> {
+        let synced = DoviRpu::parse_unspec62_nalu(nal_data).and_then(|mut rpu| {
+            let Some(mut level6) = rpu.level6()? else {
+                return Ok(None);
+            };
+
+            level6.copy_hdr10(self.state.last_mdcv.as_ref(), self.state.last_cll.as_ref());
+            rpu.set_level6(&level6);
+
+            Ok(Some(rpu.write_unspec62_nalu()))
+        });
+
+        let data = match synced {
+            Ok(Some(data)) => Some(data),
+            Ok(None) => {
+                self.state.unsynced_rpus += 1;
+                log::warn!(
+                    offset = self.nal_offset(nal),
+                    access_unit = self.state.frame_index;
+                    "Passing through Dolby Vision RPU without L6 metadata"
+                );
+                None
+            }
+            Err(e) => {
+                self.state.unsynced_rpus += 1;
+                log::warn!(
+                    offset = self.nal_offset(nal),
+                    access_unit = self.state.frame_index,
+                    reason:% = format!("{e:#}");
+                    "Passing through unsupported Dolby Vision RPU"
+                );
+                None
+            }
+        };
+
+        NALUnit::write_with_preset(
+            &mut self.writer,
+            data.as_deref().unwrap_or(nal_data),
+            StartCodePreset::Four,
+            nal.nal_type,
+            false,
+        )
+    }
+}
+
+impl<W: Write + Send> Processor<W> {
+    /// Same as [`Self::edit_file`], with the reading and NAL splitting, editing and writing
+    /// running on separate threads. The output is identical.
+    ///
+    /// The editing stays on a single thread, the edits depending on the previous access units.
+    pub fn edit_file_pipelined<P: AsRef<Path>>(&mut self, input: P) -> Result<()> {
+        let input = input.as_ref();
+        let format = self.prepare_file_edit(input)?;
+
+        // The chunks and buffers are sent back once used, to be reused
+        let (chunks_tx, chunks) = sync_channel::<ParsedChunk>(PIPELINE_DEPTH);
+        let (edited_chunks_tx, edited_chunks) = sync_channel::<ParsedChunk>(PIPELINE_DEPTH);
+        let (buffers_tx, buffers) = sync_channel::<Vec<u8>>(PIPELINE_DEPTH);
+        let (written_buffers_tx, written_buffers) = sync_channel::<Vec<u8>>(PIPELINE_DEPTH);
+
+        let mut splitter = ChunkSender {
+            input: self.input.clone(),
+            progress_bar: self.progress_bar.clone(),
+            chunks: chunks_tx,
+            edited_chunks,
+        };
+        let mut editor = self.with_writer(ChunkWriter::new(buffers_tx, written_buffers));
+        let writer = &mut self.writer;
+
+        let (split_res, edit_res, write_res) = std::thread::scope(|scope| {
+            let split = scope.spawn(move || -> Result<()> {
+                let file_path = (format != IoFormat::RawStdin).then_some(input);
+                Self::hevc_processor(format).process_file(&mut splitter, file_path)
+            });
+
+            let write = scope.spawn(move || -> Result<()> {
+                for buffer in buffers {
+                    writer.write_all(&buffer)?;
+                    // Dropped when the editing stage has enough buffers
+                    let _ = written_buffers_tx.try_send(buffer);
+                }
+                writer.flush()?;
+
+                Ok(())
+            });
+
+            let parser = HevcParser::default();
+            let edit_res = chunks
+                .iter()
+                .try_for_each(|chunk| {
+                    editor.process_nals(&parser, &chunk.nals, &chunk.data)?;
+                    let _ = edited_chunks_tx.try_send(chunk);
+
+                    Ok(())
+                })
+                .and_then(|()| editor.finalize(&parser));
+
+            // Stops the other stages
+            drop(chunks);
+            editor.writer.close();
+
+            let join = |res: std::thread::Result<Result<()>>| {
+                res.unwrap_or_else(|e| std::panic::resume_unwind(e))
+            };
+            (join(split.join()), edit_res, join(write.join()))
+        });
+
+        self.state = editor.state;
+
+        // The stages stopped by another's error fail as well, only the first error is relevant
+        write_res?;
+        edit_res?;
+        split_res?;
+
+        self.log_finished();
+
+        Ok(())
+    }
 }
 
-impl IoProcessor for Processor {
+impl Processor<Vec<u8>> {
+    /// Edits NAL units held in memory, usually complete access units.
+    /// Configs requiring a first pass over the input are not supported.
+    pub fn edit_buffer(config: &EditConfig, data: &[u8], framing: NalFraming) -> Result<Vec<u8>> {
+        let mut processor = Self::new(config.clone(), Vec::with_capacity(data.len()))?;
+        ensure!(
+            !processor.config.requires_scan(),
+            "The config requires reading the input twice, only files are supported"
+        );
+
+        let mut parser = HevcParser::default();
+        let nals = split_buffer_nals(&mut parser, data, framing)?;
+
+        processor.process_nals(&parser, &nals, data)?;
+        processor.finalize(&parser)?;
+
+        let edited = processor.into_writer();
+
+        match framing {
+            NalFraming::AnnexB => Ok(edited),
+            NalFraming::LengthPrefixed(length_size) => {
+                let nals = split_buffer_nals(&mut parser, &edited, NalFraming::AnnexB)?;
+                length_prefix_nals(&edited, &nals, length_size)
+            }
+        }
+    }
+}
+
+impl<W: Write> IoProcessor for Processor<W> {
     fn input(&self) -> &std::path::PathBuf {
         &self.input
     }
@@ -139,62 +1146,74 @@ impl IoProcessor for Processor {
     }
 
     fn process_nals(&mut self, _parser: &HevcParser, nals: &[NALUnit], chunk: &[u8]) -> Result<()> {
-        for nal in nals {
-            let nal_data = &chunk[nal.start..nal.end];
+        self.check_interrupted()?;
 
-            if nal.nal_type == NAL_SEI_PREFIX {
-                let sei_payload = clear_start_code_emulation_prevention_3_byte(nal_data);
-                let messages = SeiMessage::parse_sei_rbsp(&sei_payload)?;
-
-                if !messages
-                    .iter()
-                    .any(|e| SeiPayloadType::try_from(e.payload_type).is_ok())
-                {
-                    // No message that can be edited, rewrite NAL
-                    NALUnit::write_with_preset(
-                        &mut self.writer,
-                        nal_data,
-                        StartCodePreset::Four,
-                        nal.nal_type,
-                        false,
-                    )?;
-
-                    continue;
-                }
+        for (i, nal) in nals.iter().enumerate() {
+            let nal_data = &chunk[nal.start..nal.end];
 
-                let mut edited_seis = messages
-                    .iter()
-                    .map(|msg| Self::get_edited_sei_for_message(&sei_payload, msg, &self.config));
+            self.update_access_unit(nal, nal_data)?;
 
-                if messages.len() > 1 {
-                    let mut new_nals = Vec::with_capacity(messages.len());
+            if matches!(nal.nal_type, NAL_UNSPEC62 | NAL_UNSPEC63) && self.config.remove_dovi() {
+                self.state.removed_dovi_nals += 1;
+                log::trace!(
+                    offset = self.nal_offset(nal),
+                    access_unit = self.state.frame_index,
+                    nal_type = nal.nal_type;
+                    "Removed Dolby Vision NAL"
+                );
+                continue;
+            }
 
-                    // Split all messages into separate NALs, even if they're not edited
-                    for edited_res in edited_seis {
-                        let edited_sei = edited_res?;
-                        new_nals.push(edited_sei.encode_to_nal(&sei_payload)?);
+            if nal.nal_type == NAL_SEI_PREFIX {
+                let new_nals = match self.edited_sei_nals(&nals[i..], chunk) {
+                    Ok(new_nals) => new_nals,
+                    Err(e) if self.tolerant => {
+                        self.add_malformed_nal(nal, e);
+                        None
                     }
+                    Err(e) => return Err(e),
+                };
 
-                    for data in new_nals {
-                        NALUnit::write_with_preset(
-                            &mut self.writer,
-                            &data,
-                            StartCodePreset::Four,
-                            nal.nal_type,
-                            false,
-                        )?;
-                    }
-                } else if let Some(edited_res) = edited_seis.next_back() {
-                    let final_data = edited_res?.encode_to_nal(&sei_payload)?;
+                match new_nals {
+                    Some((new_nals, edited_messages)) => {
+                        for data in new_nals {
+                            NALUnit::write_with_preset(
+                                &mut self.writer,
+                                &data,
+                                StartCodePreset::Four,
+                                nal.nal_type,
+                                false,
+                            )?;
+                        }
 
-                    NALUnit::write_with_preset(
+                        // Only counted once written
+                        self.count_edited_seis(edited_messages);
+                    }
+                    // Rewrite the NAL as is
+                    None => NALUnit::write_with_preset(
                         &mut self.writer,
-                        &final_data,
+                        nal_data,
                         StartCodePreset::Four,
                         nal.nal_type,
                         false,
-                    )?;
+                    )?,
                 }
+            } else if nal.nal_type == NAL_SPS
+                && let Some(vui) = self.config.vui.as_ref()
+            {
+                let data = edit_sps_vui(nal_data, vui)?;
+
+                NALUnit::write_with_preset(
+                    &mut self.writer,
+                    &data,
+                    StartCodePreset::Four,
+                    nal.nal_type,
+                    false,
+                )?;
+            } else if nal.nal_type == NAL_UNSPEC62
+                && self.config.l6_sync() == Some(DoviL6Sync::FromHdr10)
+            {
+                self.write_synced_rpu(nal, nal_data)?;
             } else {
                 NALUnit::write_with_preset(
                     &mut self.writer,
@@ -206,39 +1225,91 @@ impl IoProcessor for Processor {
             }
         }
 
+        self.update_stream_offset(nals, chunk);
+
+        if let Some(progress) = self.state.progress_events.as_mut() {
+            progress.bytes = self.state.stream_offset;
+            progress.nals += nals.len() as u64;
+            progress.update();
+        }
+
         Ok(())
     }
 
     fn finalize(&mut self, _parser: &HevcParser) -> Result<()> {
+        self.check_interrupted()?;
+
+        // The scanned frames can differ from the access units found while editing
+        ensure!(
+            self.state.injected_hdr10plus.is_empty()
+                || self.state.injected_hdr10plus.len() == self.state.frame_index + 1,
+            "HDR10+ metadata was injected for {} frames, but {} access units were edited",
+            self.state.injected_hdr10plus.len(),
+            self.state.frame_index + 1
+        );
+
         self.progress_bar.finish_and_clear();
         self.writer.flush()?;
 
+        if let Some(progress) = self.state.progress_events.as_mut() {
+            progress.finish();
+        }
+
         Ok(())
     }
 }
 
 impl EditedSei<'_> {
-    pub const fn payload_type(&self) -> u8 {
+    const fn payload_type(&self) -> u8 {
         match self {
             Self::Mdcv(_) => SeiPayloadType::MasteringDisplayColourVolume as u8,
             Self::Cll(_) => SeiPayloadType::ContentLightLevel as u8,
+            Self::Atc(_) => SeiPayloadType::AlternativeTransferCharacteristics as u8,
+            Self::Ave(_) => SeiPayloadType::AmbientViewingEnvironment as u8,
+            Self::Ccv(_) => SeiPayloadType::ContentColourVolume as u8,
+            Self::Hdr10Plus(_) => SeiPayloadType::RegisteredItuTT35 as u8,
             _ => unreachable!(),
         }
     }
 
-    pub fn encode_payload(&self) -> Result<Vec<u8>> {
+    /// Logged action, `None` for the untouched messages
+    const fn action(&self) -> Option<&'static str> {
+        match self {
+            Self::None(_) => None,
+            Self::Removed => Some("removed"),
+            Self::Replaced(_) => Some("replaced"),
+            _ => Some("edited"),
+        }
+    }
+
+    fn encode_payload(&self) -> Result<Vec<u8>> {
         match self {
             Self::Mdcv((_, meta)) => meta.encode(),
             Self::Cll((_, meta)) => meta.encode(),
+            Self::Atc((_, meta)) => meta.encode(),
+            Self::Ave((_, meta)) => meta.encode(),
+            Self::Ccv((_, meta)) => meta.encode(),
+            Self::Hdr10Plus((_, meta)) => meta.encode(),
             _ => unreachable!(),
         }
     }
 
-    pub fn encode_to_nal(&self, sei_payload: &[u8]) -> Result<Vec<u8>> {
+    /// Payload written to the output, if not removed
+    fn output_payload(&self, sei_payload: &[u8]) -> Result<Option<Vec<u8>>> {
+        Ok(match self {
+            Self::Removed => None,
+            Self::None(msg) => Some(sei_message_data(msg, sei_payload).to_vec()),
+            Self::Replaced((_, data)) => Some(data.clone()),
+            _ => Some(self.encode_payload()?),
+        })
+    }
+
+    fn encode_to_nal(&self, sei_payload: &[u8]) -> Result<Vec<u8>> {
         match self {
             Self::None(msg) => {
                 encode_payload_to_sei_prefix(msg.payload_type, sei_message_data(msg, sei_payload))
             }
+            Self::Replaced((msg, data)) => encode_payload_to_sei_prefix(msg.payload_type, data),
             _ => encode_payload_to_sei_prefix(self.payload_type(), &self.encode_payload()?),
         }
     }
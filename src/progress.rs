@@ -0,0 +1,115 @@
+use std::time::{Duration, Instant};
+
+use serde::Serialize;
+
+/// Progress of an edit, reported periodically and once each phase is done
+#[derive(Serialize, Debug, Clone, PartialEq)]
+#[serde(tag = "event", rename = "progress")]
+pub struct ProgressEvent {
+    pub phase: ProgressPhase,
+
+    /// Bytes of the HEVC stream processed
+    pub bytes: u64,
+    /// Size of the HEVC stream, only known for raw HEVC files
+    pub total_bytes: Option<u64>,
+
+    /// NAL units processed
+    pub nals: u64,
+    /// SEI messages edited, replaced, removed or inserted
+    pub edited_seis: u64,
+
+    /// Seconds since the start of the phase
+    pub elapsed: f64,
+    /// Average HEVC stream bytes per second
+    pub throughput: f64,
+
+    /// Last event of the phase
+    pub done: bool,
+}
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ProgressPhase {
+    /// First pass over the input, for the configs computing values from the stream
+    Scan,
+    Edit,
+}
+
+pub type ProgressCallback = Box<dyn FnMut(&ProgressEvent) + Send>;
+
+/// Counters of the edit, calling back at most once per interval
+pub(crate) struct ProgressReporter {
+    callback: ProgressCallback,
+    interval: Duration,
+
+    phase: ProgressPhase,
+    start: Instant,
+    last_event: Instant,
+    total_bytes: Option<u64>,
+
+    pub bytes: u64,
+    pub nals: u64,
+    pub edited_seis: u64,
+}
+
+impl ProgressReporter {
+    pub fn new(callback: ProgressCallback, interval: Duration) -> Self {
+        let now = Instant::now();
+
+        Self {
+            callback,
+            interval,
+            phase: ProgressPhase::Edit,
+            start: now,
+            last_event: now,
+            total_bytes: None,
+            bytes: 0,
+            nals: 0,
+            edited_seis: 0,
+        }
+    }
+
+    /// Resets the counters for the phase
+    pub fn start(&mut self, phase: ProgressPhase, total_bytes: Option<u64>) {
+        self.phase = phase;
+        self.start = Instant::now();
+        self.last_event = self.start;
+        self.total_bytes = total_bytes;
+
+        self.bytes = 0;
+        self.nals = 0;
+        self.edited_seis = 0;
+    }
+
+    pub fn update(&mut self) {
+        if self.last_event.elapsed() >= self.interval {
+            self.report(false);
+        }
+    }
+
+    pub fn finish(&mut self) {
+        self.report(true);
+    }
+
+    fn report(&mut self, done: bool) {
+        self.last_event = Instant::now();
+
+        let elapsed = self.start.elapsed().as_secs_f64();
+        let throughput = if elapsed > 0.0 {
+            self.bytes as f64 / elapsed
+        } else {
+            0.0
+        };
+
+        (self.callback)(&ProgressEvent {
+            phase: self.phase,
+            bytes: self.bytes,
+            total_bytes: self.total_bytes,
+            nals: self.nals,
+            edited_seis: self.edited_seis,
+            elapsed,
+            throughput,
+            done,
+        });
+    }
+}
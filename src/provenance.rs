@@ -0,0 +1,135 @@
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+
+use super::cll_metadata::CllMetadata;
+use super::mdcv_metadata::MdcvMetadata;
+
+/// UUID of the user data unregistered SEI written by the tool
+pub const PROVENANCE_UUID: [u8; 16] = [
+    0x84, 0x28, 0x56, 0xE4, 0xBB, 0x92, 0x43, 0xEA, 0xB8, 0x99, 0x8D, 0x8C, 0xF3, 0x18, 0xEE, 0x06,
+];
+
+/// Record of the metadata edited in an access unit, as JSON in a user data unregistered SEI.
+/// The original values are the ones before any edit by the tool, even over multiple runs.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Provenance {
+    pub tool: String,
+    pub version: String,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub mdcv: Option<ProvenanceRecord<MdcvMetadata>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cll: Option<ProvenanceRecord<CllMetadata>>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct ProvenanceRecord<T> {
+    pub original: T,
+    pub edited: T,
+}
+
+impl Provenance {
+    pub fn new() -> Self {
+        Self {
+            tool: env!("CARGO_PKG_NAME").to_string(),
+            version: tool_version(),
+            mdcv: None,
+            cll: None,
+        }
+    }
+
+    pub fn is_provenance(data: &[u8]) -> bool {
+        data.starts_with(&PROVENANCE_UUID)
+    }
+
+    /// Parses the user data unregistered payload, if it was written by the tool
+    pub fn parse(data: &[u8]) -> Result<Option<Self>> {
+        if !Self::is_provenance(data) {
+            return Ok(None);
+        }
+
+        Ok(Some(serde_json::from_slice(
+            &data[PROVENANCE_UUID.len()..],
+        )?))
+    }
+
+    pub fn encode(&self) -> Result<Vec<u8>> {
+        let mut data = PROVENANCE_UUID.to_vec();
+        serde_json::to_writer(&mut data, self)?;
+
+        Ok(data)
+    }
+
+    pub const fn is_empty(&self) -> bool {
+        self.mdcv.is_none() && self.cll.is_none()
+    }
+
+    /// Keeps the records of both, the other's taking precedence
+    pub fn merge(&mut self, other: Self) {
+        if other.mdcv.is_some() {
+            self.mdcv = other.mdcv;
+        }
+        if other.cll.is_some() {
+            self.cll = other.cll;
+        }
+    }
+}
+
+impl Default for Provenance {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T: PartialEq + Clone> ProvenanceRecord<T> {
+    /// Record from `input` to `output`, keeping the original value of a previous record.
+    /// No record is needed when the output is the original value.
+    pub fn new(previous: Option<&Self>, input: &T, output: &T) -> Option<Self> {
+        let original = previous
+            .filter(|previous| &previous.edited == input)
+            .map_or(input, |previous| &previous.original);
+
+        (original != output).then(|| Self {
+            original: original.clone(),
+            edited: output.clone(),
+        })
+    }
+
+    /// Original value, if the metadata wasn't changed since the record
+    pub fn revert(&self, current: &T) -> Option<&T> {
+        (&self.edited == current).then_some(&self.original)
+    }
+}
+
+/// Package version, with the git description when available
+pub fn tool_version() -> String {
+    let version = env!("CARGO_PKG_VERSION");
+
+    match option_env!("VERGEN_GIT_DESCRIBE") {
+        Some(describe) => format!("{version} ({describe})"),
+        None => version.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_record_keeps_original() {
+        let first = ProvenanceRecord::new(None, &1, &2).unwrap();
+        assert_eq!(first.revert(&2), Some(&1));
+        assert_eq!(first.revert(&3), None);
+
+        // Edited again, still reverts to the first value
+        let second = ProvenanceRecord::new(Some(&first), &2, &3).unwrap();
+        assert_eq!(second.original, 1);
+
+        // Edited back to the original
+        assert!(ProvenanceRecord::new(Some(&second), &3, &1).is_none());
+
+        // Previous record doesn't apply to the changed metadata
+        let other = ProvenanceRecord::new(Some(&second), &4, &5).unwrap();
+        assert_eq!(other.original, 4);
+    }
+}
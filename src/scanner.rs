@@ -0,0 +1,315 @@
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use anyhow::{Result, anyhow, bail, ensure};
+use hevc_parser::HevcParser;
+use hevc_parser::hevc::{
+    NAL_SEI_PREFIX, NAL_UNSPEC62, NALUnit, SeiMessage, USER_DATA_REGISTERED_ITU_T_35,
+};
+use hevc_parser::io::processor::{HevcProcessor, HevcProcessorOpts};
+use hevc_parser::io::{IoFormat, IoProcessor};
+use hevc_parser::utils::clear_start_code_emulation_prevention_3_byte;
+use indicatif::ProgressBar;
+
+use crate::progress::{ProgressPhase, ProgressReporter};
+
+use super::cll_metadata::CllMetadata;
+use super::dovi_rpu::{DoviRpu, ExtMetadataBlockLevel6, pq_to_nits};
+use super::edit_config::CllSource;
+use super::hdr10plus_metadata::Hdr10PlusMetadata;
+use super::utils::{consumed_chunk_len, sei_message_data, stream_len};
+
+/// First pass over the input, gathering the metadata required for editing
+pub struct MetadataScanner<'a> {
+    input: PathBuf,
+    mode: ScanMode,
+    interrupted: Option<&'static AtomicBool>,
+
+    progress_bar: ProgressBar,
+    progress_events: Option<&'a mut ProgressReporter>,
+
+    /// In nits
+    max_content_light_level: Option<f64>,
+    max_frame_average_light_level: Option<f64>,
+
+    level6: Option<ExtMetadataBlockLevel6>,
+
+    /// RPUs that couldn't be parsed, with the first error
+    skipped_rpus: usize,
+    skipped_rpu_reason: Option<String>,
+
+    /// Presentation index of each frame, in decode order
+    presentation_order: Vec<usize>,
+}
+
+/// Settings of the editing processor that also apply to the scan
+#[derive(Default)]
+pub struct ScanOptions<'a> {
+    /// Stops the scan at the next chunk once set
+    pub interrupted: Option<&'static AtomicBool>,
+    pub show_progress: bool,
+
+    /// Reports the scan phase before the edit
+    pub progress_events: Option<&'a mut ProgressReporter>,
+}
+
+#[derive(Debug, Clone, Copy)]
+enum ScanMode {
+    LightLevel(CllSource),
+    DoviLevel6,
+    FrameOrder,
+}
+
+impl<'a> MetadataScanner<'a> {
+    fn scan(
+        format: &IoFormat,
+        input: &Path,
+        mode: ScanMode,
+        options: &'a mut ScanOptions<'_>,
+    ) -> Result<Self> {
+        if let IoFormat::RawStdin = format {
+            bail!("Scanning the metadata requires reading the input twice, stdin is not supported");
+        }
+
+        let progress_bar = if options.show_progress {
+            crate::utils::initialize_progress_bar(format, input)?
+        } else {
+            ProgressBar::hidden()
+        };
+
+        let mut progress_events = options.progress_events.as_deref_mut();
+        if let Some(progress) = progress_events.as_mut() {
+            progress.start(ProgressPhase::Scan, stream_len(format, input)?);
+        }
+
+        let mut scanner = Self {
+            input: input.to_path_buf(),
+            mode,
+            interrupted: options.interrupted,
+            progress_bar,
+            progress_events,
+            max_content_light_level: None,
+            max_frame_average_light_level: None,
+            level6: None,
+            skipped_rpus: 0,
+            skipped_rpu_reason: None,
+            presentation_order: Vec::new(),
+        };
+
+        let processor_opts = HevcProcessorOpts {
+            parse_nals: matches!(mode, ScanMode::FrameOrder),
+            ..Default::default()
+        };
+        let mut processor = HevcProcessor::new(format.clone(), processor_opts, 100_000);
+        processor.process_file(&mut scanner, Some(input))?;
+
+        Ok(scanner)
+    }
+
+    /// Computes MaxCLL/MaxFALL from the dynamic metadata present in the stream
+    pub fn scan_light_level(
+        format: &IoFormat,
+        input: &Path,
+        source: CllSource,
+        options: &mut ScanOptions,
+    ) -> Result<CllMetadata> {
+        let scanner = MetadataScanner::scan(format, input, ScanMode::LightLevel(source), options)?;
+
+        let (Some(max_cll), Some(max_fall)) = (
+            scanner.max_content_light_level,
+            scanner.max_frame_average_light_level,
+        ) else {
+            bail!("No {} metadata found in the input", source);
+        };
+
+        Ok(CllMetadata {
+            max_content_light_level: max_cll.round() as u16,
+            max_frame_average_light_level: max_fall.round() as u16,
+        })
+    }
+
+    /// L6 metadata of the first Dolby Vision RPU containing it
+    pub fn scan_level6(
+        format: &IoFormat,
+        input: &Path,
+        options: &mut ScanOptions,
+    ) -> Result<ExtMetadataBlockLevel6> {
+        let scanner = MetadataScanner::scan(format, input, ScanMode::DoviLevel6, options)?;
+
+        scanner
+            .level6
+            .ok_or_else(|| anyhow!("No Dolby Vision L6 metadata found in the input"))
+    }
+
+    /// Presentation index of each frame, in decode order
+    pub fn scan_frame_order(
+        format: &IoFormat,
+        input: &Path,
+        options: &mut ScanOptions,
+    ) -> Result<Vec<usize>> {
+        let scanner = MetadataScanner::scan(format, input, ScanMode::FrameOrder, options)?;
+
+        Ok(scanner.presentation_order)
+    }
+
+    fn update_light_level(&mut self, frame_max_cll: f64, frame_average: f64) {
+        self.max_content_light_level = Some(
+            self.max_content_light_level
+                .map_or(frame_max_cll, |v| v.max(frame_max_cll)),
+        );
+        self.max_frame_average_light_level = Some(
+            self.max_frame_average_light_level
+                .map_or(frame_average, |v| v.max(frame_average)),
+        );
+    }
+
+    fn process_sei(&mut self, nal_data: &[u8]) -> Result<()> {
+        let sei_payload = clear_start_code_emulation_prevention_3_byte(nal_data);
+        let messages = SeiMessage::parse_sei_rbsp(&sei_payload)?;
+
+        let hdr10plus_msgs = messages
+            .iter()
+            .filter(|msg| msg.payload_type == USER_DATA_REGISTERED_ITU_T_35)
+            .map(|msg| sei_message_data(msg, &sei_payload))
+            .filter(|data| Hdr10PlusMetadata::is_hdr10plus(data));
+
+        for data in hdr10plus_msgs {
+            let meta = Hdr10PlusMetadata::parse(data)?;
+            self.update_light_level(
+                meta.max_content_light_level(),
+                meta.frame_average_light_level(),
+            );
+        }
+
+        Ok(())
+    }
+
+    fn process_rpu(&mut self, nal_data: &[u8]) -> Result<()> {
+        let rpu = DoviRpu::parse_unspec62_nalu(nal_data)?;
+
+        match self.mode {
+            ScanMode::LightLevel(_) => {
+                if let Some(level1) = rpu.level1()? {
+                    self.update_light_level(pq_to_nits(level1.max_pq), pq_to_nits(level1.avg_pq));
+                }
+            }
+            ScanMode::DoviLevel6 => {
+                if self.level6.is_none() {
+                    self.level6 = rpu.level6()?;
+                }
+            }
+            ScanMode::FrameOrder => (),
+        }
+
+        Ok(())
+    }
+}
+
+impl IoProcessor for MetadataScanner<'_> {
+    fn input(&self) -> &PathBuf {
+        &self.input
+    }
+
+    fn update_progress(&mut self, delta: u64) {
+        self.progress_bar.inc(delta);
+    }
+
+    fn process_nals(&mut self, _parser: &HevcParser, nals: &[NALUnit], chunk: &[u8]) -> Result<()> {
+        if self
+            .interrupted
+            .is_some_and(|flag| flag.load(Ordering::Relaxed))
+        {
+            bail!("Interrupted");
+        }
+
+        for nal in nals {
+            let nal_data = &chunk[nal.start..nal.end];
+
+            match (self.mode, nal.nal_type) {
+                (ScanMode::LightLevel(CllSource::Hdr10Plus), NAL_SEI_PREFIX) => {
+                    self.process_sei(nal_data)?
+                }
+                (
+                    ScanMode::LightLevel(CllSource::DolbyVision) | ScanMode::DoviLevel6,
+                    NAL_UNSPEC62,
+                ) => {
+                    // Unsupported RPUs are skipped, the metadata comes from the others
+                    if let Err(e) = self.process_rpu(nal_data) {
+                        self.skipped_rpus += 1;
+                        self.skipped_rpu_reason
+                            .get_or_insert_with(|| format!("{e:#}"));
+                    }
+                }
+                _ => (),
+            }
+        }
+
+        if let Some(progress) = self.progress_events.as_mut() {
+            progress.bytes += consumed_chunk_len(nals, chunk) as u64;
+            progress.nals += nals.len() as u64;
+            progress.update();
+        }
+
+        Ok(())
+    }
+
+    fn finalize(&mut self, parser: &HevcParser) -> Result<()> {
+        self.progress_bar.finish_and_clear();
+
+        if let Some(progress) = self.progress_events.as_mut() {
+            progress.finish();
+        }
+
+        if let Some(reason) = self.skipped_rpu_reason.as_ref() {
+            log::warn!(
+                count = self.skipped_rpus,
+                reason:% = reason;
+                "Skipped unsupported Dolby Vision RPUs while scanning"
+            );
+        }
+
+        if let ScanMode::FrameOrder = self.mode {
+            let frames = parser.ordered_frames();
+
+            self.presentation_order = vec![0; frames.len()];
+            for frame in frames {
+                let decoded_index = frame.decoded_number as usize;
+
+                ensure!(decoded_index < frames.len(), "Invalid decoded frame number");
+                self.presentation_order[decoded_index] = frame.presentation_number as usize;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+    use std::sync::atomic::AtomicBool;
+
+    use hevc_parser::io::IoFormat;
+
+    use super::{MetadataScanner, ScanOptions};
+    use crate::edit_config::CllSource;
+
+    #[test]
+    fn interrupted_scan() {
+        static INTERRUPTED: AtomicBool = AtomicBool::new(true);
+
+        let mut options = ScanOptions {
+            interrupted: Some(&INTERRUPTED),
+            ..Default::default()
+        };
+        let input = Path::new("assets/regular.hevc");
+
+        let res = MetadataScanner::scan_light_level(
+            &IoFormat::Raw,
+            input,
+            CllSource::Hdr10Plus,
+            &mut options,
+        );
+        assert_eq!(res.unwrap_err().to_string(), "Interrupted");
+    }
+}
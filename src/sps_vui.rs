@@ -0,0 +1,632 @@
+use anyhow::{Result, anyhow, ensure};
+use bitvec_helpers::{bitslice_reader::BitSliceReader, bitstream_io_writer::BitstreamIoWriter};
+use serde::{Deserialize, Serialize};
+
+use hevc_parser::utils::{
+    add_start_code_emulation_prevention_3_byte, clear_start_code_emulation_prevention_3_byte,
+};
+
+use super::edit_config::EditVuiMetadata;
+
+const VIDEO_FORMAT_UNSPECIFIED: u8 = 5;
+const COLOUR_UNSPECIFIED: u8 = 2;
+
+/// VUI video signal type, describing the colour signalling of the stream
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct VideoSignalType {
+    pub video_format: u8,
+    pub video_full_range_flag: bool,
+
+    pub colour_description_present_flag: bool,
+    pub colour_primaries: u8,
+    pub transfer_characteristics: u8,
+    pub matrix_coeffs: u8,
+}
+
+/// Bit positions in the SPS RBSP, up to the VUI video signal type
+#[derive(Debug, Clone)]
+struct SpsVuiLayout {
+    /// Position of `vui_parameters_present_flag`
+    vui_parameters_present_pos: usize,
+
+    /// Position of `video_signal_type_present_flag`, when the VUI is present
+    video_signal_type_pos: Option<usize>,
+    /// Position right after the video signal type
+    video_signal_type_end: usize,
+
+    video_signal_type: Option<VideoSignalType>,
+}
+
+/// Short term reference picture set, only the delta POCs are kept
+#[derive(Debug, Clone, Default)]
+struct ShortTermRps {
+    delta_poc_s0: Vec<i64>,
+    delta_poc_s1: Vec<i64>,
+}
+
+/// Rewrites the VUI video signal type of a SPS NAL, without start code.
+/// The VUI is inserted if not present, the rest of the SPS is copied as is.
+pub fn edit_sps_vui(nal_data: &[u8], edit: &EditVuiMetadata) -> Result<Vec<u8>> {
+    let rbsp = clear_start_code_emulation_prevention_3_byte(nal_data);
+    let layout = SpsVuiLayout::parse(&rbsp)?;
+
+    let new_signal = edit.apply(layout.video_signal_type);
+    if layout.video_signal_type == Some(new_signal) {
+        return Ok(nal_data.to_vec());
+    }
+
+    let stop_bit_pos = rbsp_stop_one_bit_position(&rbsp)?;
+    ensure!(
+        stop_bit_pos >= layout.video_signal_type_end,
+        "Invalid SPS RBSP trailing bits"
+    );
+
+    let mut reader = BitSliceReader::new(&rbsp);
+    let mut writer = BitstreamIoWriter::with_capacity(rbsp.len() + 8);
+
+    if let Some(video_signal_type_pos) = layout.video_signal_type_pos {
+        copy_bits(&mut reader, &mut writer, video_signal_type_pos)?;
+        new_signal.write(&mut writer)?;
+
+        reader.skip_n(layout.video_signal_type_end - video_signal_type_pos)?;
+    } else {
+        copy_bits(&mut reader, &mut writer, layout.vui_parameters_present_pos)?;
+        reader.skip_n(1)?;
+
+        writer.write_bit(true)?; // vui_parameters_present_flag
+        writer.write_bit(false)?; // aspect_ratio_info_present_flag
+        writer.write_bit(false)?; // overscan_info_present_flag
+
+        new_signal.write(&mut writer)?;
+
+        writer.write_bit(false)?; // chroma_loc_info_present_flag
+        writer.write_bit(false)?; // neutral_chroma_indication_flag
+        writer.write_bit(false)?; // field_seq_flag
+        writer.write_bit(false)?; // frame_field_info_present_flag
+        writer.write_bit(false)?; // default_display_window_flag
+        writer.write_bit(false)?; // vui_timing_info_present_flag
+        writer.write_bit(false)?; // bitstream_restriction_flag
+    }
+
+    let remaining = stop_bit_pos - reader.position();
+    copy_bits(&mut reader, &mut writer, remaining)?;
+
+    // rbsp_trailing_bits
+    writer.write_bit(true)?;
+    writer.byte_align()?;
+
+    let mut data = writer.into_inner();
+    add_start_code_emulation_prevention_3_byte(&mut data);
+
+    Ok(data)
+}
+
+impl SpsVuiLayout {
+    fn parse(rbsp: &[u8]) -> Result<Self> {
+        let mut reader = BitSliceReader::new(rbsp);
+
+        // NAL header
+        reader.skip_n(16)?;
+
+        // sps_video_parameter_set_id
+        reader.skip_n(4)?;
+        let max_sub_layers_minus1: u8 = reader.get_n(3)?;
+        // sps_temporal_id_nesting_flag
+        reader.skip_n(1)?;
+
+        skip_profile_tier_level(&mut reader, max_sub_layers_minus1)?;
+
+        // sps_seq_parameter_set_id
+        reader.get_ue()?;
+
+        let chroma_format_idc = reader.get_ue()?;
+        if chroma_format_idc == 3 {
+            // separate_colour_plane_flag
+            reader.skip_n(1)?;
+        }
+
+        // pic_width_in_luma_samples, pic_height_in_luma_samples
+        reader.get_ue()?;
+        reader.get_ue()?;
+
+        let conformance_window_flag = reader.get()?;
+        if conformance_window_flag {
+            for _ in 0..4 {
+                reader.get_ue()?;
+            }
+        }
+
+        // bit_depth_luma_minus8, bit_depth_chroma_minus8
+        reader.get_ue()?;
+        reader.get_ue()?;
+
+        let log2_max_pic_order_cnt_lsb = reader.get_ue()? as usize + 4;
+
+        let sub_layer_ordering_info_present_flag = reader.get()?;
+        let first_sub_layer = if sub_layer_ordering_info_present_flag {
+            0
+        } else {
+            max_sub_layers_minus1
+        };
+
+        for _ in first_sub_layer..=max_sub_layers_minus1 {
+            // max_dec_pic_buffering_minus1, max_num_reorder_pics, max_latency_increase_plus1
+            for _ in 0..3 {
+                reader.get_ue()?;
+            }
+        }
+
+        // log2_min_luma_coding_block_size_minus3, log2_diff_max_min_luma_coding_block_size
+        // log2_min_luma_transform_block_size_minus2, log2_diff_max_min_luma_transform_block_size
+        // max_transform_hierarchy_depth_inter, max_transform_hierarchy_depth_intra
+        for _ in 0..6 {
+            reader.get_ue()?;
+        }
+
+        let scaling_list_enabled_flag = reader.get()?;
+        if scaling_list_enabled_flag {
+            let sps_scaling_list_data_present_flag = reader.get()?;
+
+            if sps_scaling_list_data_present_flag {
+                skip_scaling_list_data(&mut reader)?;
+            }
+        }
+
+        // amp_enabled_flag, sample_adaptive_offset_enabled_flag
+        reader.skip_n(2)?;
+
+        let pcm_enabled_flag = reader.get()?;
+        if pcm_enabled_flag {
+            // pcm_sample_bit_depth_luma_minus1, pcm_sample_bit_depth_chroma_minus1
+            reader.skip_n(8)?;
+
+            // log2_min_pcm_luma_coding_block_size_minus3, log2_diff_max_min_pcm_luma_coding_block_size
+            reader.get_ue()?;
+            reader.get_ue()?;
+
+            // pcm_loop_filter_disabled_flag
+            reader.skip_n(1)?;
+        }
+
+        let num_short_term_ref_pic_sets = reader.get_ue()? as usize;
+        let mut short_term_ref_pic_sets: Vec<ShortTermRps> =
+            Vec::with_capacity(num_short_term_ref_pic_sets);
+
+        for _ in 0..num_short_term_ref_pic_sets {
+            let rps = ShortTermRps::parse(&mut reader, &short_term_ref_pic_sets)?;
+            short_term_ref_pic_sets.push(rps);
+        }
+
+        let long_term_ref_pics_present_flag = reader.get()?;
+        if long_term_ref_pics_present_flag {
+            let num_long_term_ref_pics_sps = reader.get_ue()?;
+
+            for _ in 0..num_long_term_ref_pics_sps {
+                // lt_ref_pic_poc_lsb_sps, used_by_curr_pic_lt_sps_flag
+                reader.skip_n(log2_max_pic_order_cnt_lsb + 1)?;
+            }
+        }
+
+        // sps_temporal_mvp_enabled_flag, strong_intra_smoothing_enabled_flag
+        reader.skip_n(2)?;
+
+        let vui_parameters_present_pos = reader.position();
+        let vui_parameters_present_flag = reader.get()?;
+
+        let mut layout = Self {
+            vui_parameters_present_pos,
+            video_signal_type_pos: None,
+            video_signal_type_end: reader.position(),
+            video_signal_type: None,
+        };
+
+        if !vui_parameters_present_flag {
+            return Ok(layout);
+        }
+
+        let aspect_ratio_info_present_flag = reader.get()?;
+        if aspect_ratio_info_present_flag {
+            let aspect_ratio_idc: u8 = reader.get_n(8)?;
+
+            // EXTENDED_SAR
+            if aspect_ratio_idc == 255 {
+                // sar_width, sar_height
+                reader.skip_n(32)?;
+            }
+        }
+
+        let overscan_info_present_flag = reader.get()?;
+        if overscan_info_present_flag {
+            // overscan_appropriate_flag
+            reader.skip_n(1)?;
+        }
+
+        layout.video_signal_type_pos = Some(reader.position());
+        layout.video_signal_type = VideoSignalType::parse(&mut reader)?;
+        layout.video_signal_type_end = reader.position();
+
+        Ok(layout)
+    }
+}
+
+impl VideoSignalType {
+    fn parse(reader: &mut BitSliceReader) -> Result<Option<Self>> {
+        let video_signal_type_present_flag = reader.get()?;
+        if !video_signal_type_present_flag {
+            return Ok(None);
+        }
+
+        let mut signal = Self {
+            video_format: reader.get_n(3)?,
+            video_full_range_flag: reader.get()?,
+            colour_description_present_flag: reader.get()?,
+            ..Default::default()
+        };
+
+        if signal.colour_description_present_flag {
+            signal.colour_primaries = reader.get_n(8)?;
+            signal.transfer_characteristics = reader.get_n(8)?;
+            signal.matrix_coeffs = reader.get_n(8)?;
+        }
+
+        Ok(Some(signal))
+    }
+
+    fn write(&self, writer: &mut BitstreamIoWriter) -> Result<()> {
+        writer.write_bit(true)?; // video_signal_type_present_flag
+        writer.write::<3, u8>(self.video_format)?;
+        writer.write_bit(self.video_full_range_flag)?;
+        writer.write_bit(self.colour_description_present_flag)?;
+
+        if self.colour_description_present_flag {
+            writer.write::<8, u8>(self.colour_primaries)?;
+            writer.write::<8, u8>(self.transfer_characteristics)?;
+            writer.write::<8, u8>(self.matrix_coeffs)?;
+        }
+
+        Ok(())
+    }
+}
+
+impl Default for VideoSignalType {
+    fn default() -> Self {
+        Self {
+            video_format: VIDEO_FORMAT_UNSPECIFIED,
+            video_full_range_flag: false,
+            colour_description_present_flag: false,
+            colour_primaries: COLOUR_UNSPECIFIED,
+            transfer_characteristics: COLOUR_UNSPECIFIED,
+            matrix_coeffs: COLOUR_UNSPECIFIED,
+        }
+    }
+}
+
+impl ShortTermRps {
+    fn parse(reader: &mut BitSliceReader, previous_sets: &[ShortTermRps]) -> Result<Self> {
+        let inter_ref_pic_set_prediction_flag = if previous_sets.is_empty() {
+            false
+        } else {
+            reader.get()?
+        };
+
+        let mut rps = Self::default();
+
+        if inter_ref_pic_set_prediction_flag {
+            // delta_idx_minus1 is only present in slice headers
+            let ref_rps = previous_sets
+                .last()
+                .ok_or_else(|| anyhow!("Missing reference RPS"))?;
+
+            let delta_rps_sign = reader.get()?;
+            let abs_delta_rps_minus1 = reader.get_ue()? as i64;
+            let delta_rps = if delta_rps_sign {
+                -(abs_delta_rps_minus1 + 1)
+            } else {
+                abs_delta_rps_minus1 + 1
+            };
+
+            let num_negative = ref_rps.delta_poc_s0.len();
+            let num_delta_pocs = num_negative + ref_rps.delta_poc_s1.len();
+
+            let mut use_delta_flag = vec![true; num_delta_pocs + 1];
+            for flag in use_delta_flag.iter_mut() {
+                let used_by_curr_pic_flag = reader.get()?;
+
+                if !used_by_curr_pic_flag {
+                    *flag = reader.get()?;
+                }
+            }
+
+            // Derivation from the reference RPS, (7-61) and (7-62)
+            for (j, d_poc) in ref_rps.delta_poc_s1.iter().enumerate().rev() {
+                let d_poc = d_poc + delta_rps;
+                if d_poc < 0 && use_delta_flag[num_negative + j] {
+                    rps.delta_poc_s0.push(d_poc);
+                }
+            }
+            if delta_rps < 0 && use_delta_flag[num_delta_pocs] {
+                rps.delta_poc_s0.push(delta_rps);
+            }
+            for (j, d_poc) in ref_rps.delta_poc_s0.iter().enumerate() {
+                let d_poc = d_poc + delta_rps;
+                if d_poc < 0 && use_delta_flag[j] {
+                    rps.delta_poc_s0.push(d_poc);
+                }
+            }
+
+            for (j, d_poc) in ref_rps.delta_poc_s0.iter().enumerate().rev() {
+                let d_poc = d_poc + delta_rps;
+                if d_poc > 0 && use_delta_flag[j] {
+                    rps.delta_poc_s1.push(d_poc);
+                }
+            }
+            if delta_rps > 0 && use_delta_flag[num_delta_pocs] {
+                rps.delta_poc_s1.push(delta_rps);
+            }
+            for (j, d_poc) in ref_rps.delta_poc_s1.iter().enumerate() {
+                let d_poc = d_poc + delta_rps;
+                if d_poc > 0 && use_delta_flag[num_negative + j] {
+                    rps.delta_poc_s1.push(d_poc);
+                }
+            }
+        } else {
+            let num_negative_pics = reader.get_ue()?;
+            let num_positive_pics = reader.get_ue()?;
+
+            let mut poc = 0;
+            for _ in 0..num_negative_pics {
+                poc -= reader.get_ue()? as i64 + 1;
+                // used_by_curr_pic_s0_flag
+                reader.skip_n(1)?;
+
+                rps.delta_poc_s0.push(poc);
+            }
+
+            poc = 0;
+            for _ in 0..num_positive_pics {
+                poc += reader.get_ue()? as i64 + 1;
+                // used_by_curr_pic_s1_flag
+                reader.skip_n(1)?;
+
+                rps.delta_poc_s1.push(poc);
+            }
+        }
+
+        Ok(rps)
+    }
+}
+
+fn skip_profile_tier_level(reader: &mut BitSliceReader, max_sub_layers_minus1: u8) -> Result<()> {
+    // general profile and level
+    reader.skip_n(88 + 8)?;
+
+    let mut sub_layer_flags = Vec::with_capacity(max_sub_layers_minus1 as usize);
+    for _ in 0..max_sub_layers_minus1 {
+        // sub_layer_profile_present_flag, sub_layer_level_present_flag
+        sub_layer_flags.push((reader.get()?, reader.get()?));
+    }
+
+    if max_sub_layers_minus1 > 0 {
+        // reserved_zero_2bits
+        reader.skip_n(2 * (8 - max_sub_layers_minus1 as usize))?;
+    }
+
+    for (profile_present_flag, level_present_flag) in sub_layer_flags {
+        if profile_present_flag {
+            reader.skip_n(88)?;
+        }
+
+        if level_present_flag {
+            reader.skip_n(8)?;
+        }
+    }
+
+    Ok(())
+}
+
+fn skip_scaling_list_data(reader: &mut BitSliceReader) -> Result<()> {
+    for size_id in 0..4 {
+        let step = if size_id == 3 { 3 } else { 1 };
+
+        for _ in (0..6).step_by(step) {
+            let scaling_list_pred_mode_flag = reader.get()?;
+
+            if !scaling_list_pred_mode_flag {
+                // scaling_list_pred_matrix_id_delta
+                reader.get_ue()?;
+            } else {
+                let coef_num = 64.min(1 << (4 + (size_id << 1)));
+
+                if size_id > 1 {
+                    // scaling_list_dc_coef_minus8
+                    reader.get_se()?;
+                }
+
+                for _ in 0..coef_num {
+                    // scaling_list_delta_coef
+                    reader.get_se()?;
+                }
+            }
+        }
+    }
+
+    Ok(())
+}
+
+fn rbsp_stop_one_bit_position(rbsp: &[u8]) -> Result<usize> {
+    let (last_idx, last_byte) = rbsp
+        .iter()
+        .enumerate()
+        .rfind(|(_, byte)| **byte != 0)
+        .ok_or_else(|| anyhow!("Missing rbsp_stop_one_bit"))?;
+
+    Ok(last_idx * 8 + 7 - last_byte.trailing_zeros() as usize)
+}
+
+fn copy_bits(reader: &mut BitSliceReader, writer: &mut BitstreamIoWriter, n: usize) -> Result<()> {
+    let mut remaining = n;
+
+    while remaining > 0 {
+        let bits = remaining.min(32);
+        let value: u32 = reader.get_n(bits)?;
+        writer.write_var(bits as u32, value)?;
+
+        remaining -= bits;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse_video_signal_type(nal_data: &[u8]) -> Result<Option<VideoSignalType>> {
+        let rbsp = clear_start_code_emulation_prevention_3_byte(nal_data);
+        let layout = SpsVuiLayout::parse(&rbsp)?;
+
+        Ok(layout.video_signal_type)
+    }
+
+    /// SPS from `assets/regular.hevc`
+    const SPS: &[u8] = &[
+        0x42, 0x01, 0x01, 0x22, 0x20, 0x00, 0x00, 0x03, 0x00, 0x90, 0x00, 0x00, 0x03, 0x00, 0x00,
+        0x03, 0x00, 0x99, 0xa0, 0x08, 0x08, 0x09, 0x13, 0x65, 0x95, 0x9a, 0x49, 0x32, 0xb9, 0xa8,
+        0x48, 0x80, 0x4d, 0xb0, 0x80, 0x00, 0x01, 0xf4, 0x80, 0x00, 0x2e, 0xe0, 0x31, 0x5e, 0x4b,
+        0x2f, 0x00, 0x00, 0x98, 0x96, 0x80, 0x00, 0x4c, 0x4b, 0x44,
+    ];
+
+    fn edit(
+        colour_primaries: u8,
+        transfer_characteristics: u8,
+        matrix_coeffs: u8,
+    ) -> EditVuiMetadata {
+        EditVuiMetadata {
+            video_full_range_flag: Some(false),
+            colour_primaries: Some(colour_primaries),
+            transfer_characteristics: Some(transfer_characteristics),
+            matrix_coeffs: Some(matrix_coeffs),
+        }
+    }
+
+    #[test]
+    fn test_edit_roundtrip() -> Result<()> {
+        let original = parse_video_signal_type(SPS)?.unwrap();
+        assert_eq!(
+            original,
+            VideoSignalType {
+                video_format: 5,
+                video_full_range_flag: false,
+                colour_description_present_flag: true,
+                colour_primaries: 9,
+                transfer_characteristics: 16,
+                matrix_coeffs: 9,
+            }
+        );
+
+        let edited = edit_sps_vui(SPS, &edit(1, 1, 1))?;
+        let signal = parse_video_signal_type(&edited)?.unwrap();
+        assert_eq!(
+            (
+                signal.colour_primaries,
+                signal.transfer_characteristics,
+                signal.matrix_coeffs
+            ),
+            (1, 1, 1)
+        );
+
+        // round trips back to original
+        let restored = edit_sps_vui(&edited, &edit(9, 16, 9))?;
+        assert_eq!(restored, SPS);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_insert_vui() -> Result<()> {
+        let mut writer = BitstreamIoWriter::with_capacity(32);
+
+        writer.write::<16, u16>(0x4201)?; // NAL header
+        writer.write::<4, u8>(0)?; // sps_video_parameter_set_id
+        writer.write::<3, u8>(0)?; // sps_max_sub_layers_minus1
+        writer.write_bit(true)?; // sps_temporal_id_nesting_flag
+
+        // Main 10 profile, level 5.1
+        writer.write::<8, u8>(2)?;
+        writer.write::<32, u32>(0x2000_0000)?;
+        writer.write::<16, u16>(0x9000)?;
+        writer.write::<32, u32>(0)?;
+        writer.write::<8, u8>(153)?;
+
+        writer.write_ue(0)?; // sps_seq_parameter_set_id
+        writer.write_ue(1)?; // chroma_format_idc
+        writer.write_ue(3840)?;
+        writer.write_ue(2160)?;
+        writer.write_bit(false)?; // conformance_window_flag
+        writer.write_ue(2)?;
+        writer.write_ue(2)?;
+        writer.write_ue(4)?; // log2_max_pic_order_cnt_lsb_minus4
+        writer.write_bit(true)?; // sps_sub_layer_ordering_info_present_flag
+        writer.write_ue(4)?;
+        writer.write_ue(2)?;
+        writer.write_ue(0)?;
+        for v in [0, 3, 0, 3, 1, 1] {
+            writer.write_ue(v)?;
+        }
+        writer.write_bit(false)?; // scaling_list_enabled_flag
+        writer.write_bit(true)?; // amp_enabled_flag
+        writer.write_bit(true)?; // sample_adaptive_offset_enabled_flag
+        writer.write_bit(false)?; // pcm_enabled_flag
+
+        // Two RPS, the second one predicted from the first
+        writer.write_ue(2)?;
+        writer.write_ue(1)?;
+        writer.write_ue(0)?;
+        writer.write_ue(0)?;
+        writer.write_bit(true)?;
+        writer.write_bit(true)?; // inter_ref_pic_set_prediction_flag
+        writer.write_bit(true)?; // delta_rps_sign
+        writer.write_ue(0)?;
+        writer.write_bit(true)?;
+        writer.write_bit(true)?;
+
+        writer.write_bit(false)?; // long_term_ref_pics_present_flag
+        writer.write_bit(true)?; // sps_temporal_mvp_enabled_flag
+        writer.write_bit(true)?; // strong_intra_smoothing_enabled_flag
+        writer.write_bit(false)?; // vui_parameters_present_flag
+        writer.write_bit(false)?; // sps_extension_present_flag
+
+        writer.write_bit(true)?; // rbsp_stop_one_bit
+        writer.byte_align()?;
+
+        let mut sps = writer.into_inner();
+        add_start_code_emulation_prevention_3_byte(&mut sps);
+
+        assert_eq!(parse_video_signal_type(&sps)?, None);
+
+        let edited = edit_sps_vui(&sps, &edit(9, 16, 9))?;
+        let signal = parse_video_signal_type(&edited)?.unwrap();
+
+        assert_eq!(
+            signal,
+            VideoSignalType {
+                video_format: VIDEO_FORMAT_UNSPECIFIED,
+                video_full_range_flag: false,
+                colour_description_present_flag: true,
+                colour_primaries: 9,
+                transfer_characteristics: 16,
+                matrix_coeffs: 9,
+            }
+        );
+
+        // VUI flags and video signal type (2 + 6 + 24 + 7 bits) were inserted before the extension flag
+        let rbsp = clear_start_code_emulation_prevention_3_byte(&edited);
+        let original_rbsp = clear_start_code_emulation_prevention_3_byte(&sps);
+        assert_eq!(
+            rbsp_stop_one_bit_position(&rbsp)?,
+            rbsp_stop_one_bit_position(&original_rbsp)? + 39
+        );
+
+        Ok(())
+    }
+}
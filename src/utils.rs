@@ -1,14 +1,16 @@
-use std::path::PathBuf;
 use std::{fs::File, path::Path};
 
-use anyhow::{Result, bail, ensure};
+use anyhow::{Result, anyhow, bail, ensure};
 use bitvec_helpers::bitstream_io_writer::BitstreamIoWriter;
-use hevc_parser::hevc::{NAL_SEI_PREFIX, SeiMessage};
+use hevc_parser::HevcParser;
+use hevc_parser::hevc::{NAL_SEI_PREFIX, NALUnit, SeiMessage};
 use hevc_parser::utils::add_start_code_emulation_prevention_3_byte;
 use indicatif::{ProgressBar, ProgressStyle};
 
 use hevc_parser::io::IoFormat;
 
+use super::processor::NalFraming;
+
 pub fn initialize_progress_bar<P: AsRef<Path>>(format: &IoFormat, input: P) -> Result<ProgressBar> {
     let pb: ProgressBar;
     let bytes_count;
@@ -31,13 +33,26 @@ pub fn initialize_progress_bar<P: AsRef<Path>>(format: &IoFormat, input: P) -> R
     Ok(pb)
 }
 
-pub fn input_from_either(in1: Option<PathBuf>, in2: Option<PathBuf>) -> Result<PathBuf> {
-    match in1 {
-        Some(in1) => Ok(in1),
-        None => match in2 {
-            Some(in2) => Ok(in2),
-            None => bail!("No input file provided. See `hevc_hdr_editor --help`"),
-        },
+/// Size of the HEVC stream, only known for raw HEVC files
+pub fn stream_len(format: &IoFormat, input: &Path) -> Result<Option<u64>> {
+    Ok(match format {
+        IoFormat::Raw => Some(std::fs::metadata(input)?.len()),
+        _ => None,
+    })
+}
+
+/// Bytes of the chunk up to the NALs carried over to the next chunk.
+/// The last NAL is followed by the start code of the carried over NALs,
+/// a chunk without NALs being carried over from its first start code.
+pub fn consumed_chunk_len(nals: &[NALUnit], chunk: &[u8]) -> usize {
+    match nals.last() {
+        Some(nal) if chunk[nal.end..].starts_with(&[0, 0, 0, 1]) => nal.end + 1,
+        Some(nal) if nal.end < chunk.len() => nal.end,
+        Some(_) => chunk.len(),
+        None => chunk
+            .windows(3)
+            .position(|window| window == [0, 0, 1])
+            .unwrap_or(chunk.len()),
     }
 }
 
@@ -48,6 +63,123 @@ pub fn sei_message_data<'a>(msg: &SeiMessage, sei_payload: &'a [u8]) -> &'a [u8]
     &sei_payload[start..end]
 }
 
+pub fn decode_hex(value: &str) -> Result<Vec<u8>> {
+    ensure!(
+        value.len().is_multiple_of(2) && value.is_ascii(),
+        "Invalid hex string: {value}"
+    );
+
+    (0..value.len())
+        .step_by(2)
+        .map(|i| {
+            u8::from_str_radix(&value[i..i + 2], 16)
+                .map_err(|_| anyhow!("Invalid hex string: {value}"))
+        })
+        .collect()
+}
+
+pub fn encode_hex(data: &[u8]) -> String {
+    data.iter().map(|b| format!("{b:02x}")).collect()
+}
+
+/// Standard base64 alphabet, with optional padding
+pub fn decode_base64(value: &str) -> Result<Vec<u8>> {
+    let value = value.trim_end_matches('=');
+    let mut data = Vec::with_capacity(value.len() * 3 / 4);
+
+    let mut buffer = 0_u32;
+    let mut bits = 0;
+
+    for c in value.bytes() {
+        let sextet = match c {
+            b'A'..=b'Z' => c - b'A',
+            b'a'..=b'z' => c - b'a' + 26,
+            b'0'..=b'9' => c - b'0' + 52,
+            b'+' => 62,
+            b'/' => 63,
+            _ => bail!("Invalid base64 string: {value}"),
+        };
+
+        buffer = (buffer << 6) | sextet as u32;
+        bits += 6;
+
+        if bits >= 8 {
+            bits -= 8;
+            data.push((buffer >> bits) as u8);
+        }
+    }
+
+    Ok(data)
+}
+
+/// NAL units of an in-memory buffer, the positions exclude the start code or length
+pub fn split_buffer_nals(
+    parser: &mut HevcParser,
+    data: &[u8],
+    framing: NalFraming,
+) -> Result<Vec<NALUnit>> {
+    match framing {
+        NalFraming::AnnexB => {
+            let mut offsets = Vec::new();
+            parser.get_offsets(data, &mut offsets);
+
+            match offsets.last() {
+                Some(&last) => parser.split_nals(data, &offsets, last, false),
+                None => Ok(Vec::new()),
+            }
+        }
+        NalFraming::LengthPrefixed(length_size) => {
+            ensure!(
+                (1..=4).contains(&length_size),
+                "Invalid NAL length size {length_size}, expected 1 to 4 bytes"
+            );
+
+            let mut nals = Vec::new();
+            let mut pos = 0;
+
+            while pos < data.len() {
+                let Some(length) = data.get(pos..pos + length_size) else {
+                    bail!("Truncated NAL length at offset {pos}");
+                };
+                let size = length.iter().fold(0, |acc, b| (acc << 8) | *b as usize);
+                pos += length_size;
+
+                ensure!(
+                    size > 0 && pos + size <= data.len(),
+                    "Invalid NAL size {size} at offset {pos}"
+                );
+
+                nals.push(NALUnit {
+                    start: pos,
+                    end: pos + size,
+                    nal_type: data[pos] >> 1,
+                    ..Default::default()
+                });
+                pos += size;
+            }
+
+            Ok(nals)
+        }
+    }
+}
+
+pub fn length_prefix_nals(data: &[u8], nals: &[NALUnit], length_size: usize) -> Result<Vec<u8>> {
+    let mut out = Vec::with_capacity(data.len());
+
+    for nal in nals {
+        let size = nal.end - nal.start;
+        ensure!(
+            (size as u64) < 1 << (length_size * 8),
+            "NAL size {size} doesn't fit in {length_size} bytes"
+        );
+
+        out.extend_from_slice(&(size as u32).to_be_bytes()[4 - length_size..]);
+        out.extend_from_slice(&data[nal.start..nal.end]);
+    }
+
+    Ok(out)
+}
+
 pub fn encode_payload_to_sei_prefix(payload_type: u8, payload: &[u8]) -> Result<Vec<u8>> {
     // Write NALU SEI_PREFIX header
     let mut header_writer = BitstreamIoWriter::with_capacity(payload.len());
@@ -60,14 +192,14 @@ pub fn encode_payload_to_sei_prefix(payload_type: u8, payload: &[u8]) -> Result<
 
     header_writer.write::<8, u8>(payload_type)?;
 
-    // FIXME: This should probably be 1024 but not sure how to write a longer header
-    ensure!(
-        payload.len() <= 255,
-        "Payload too large: {} bytes",
-        payload.len()
-    );
+    // payload_size is coded as a sum of 0xFF bytes and the last byte
+    let mut payload_size = payload.len();
+    while payload_size >= 0xFF {
+        header_writer.write::<8, u8>(0xFF)?;
+        payload_size -= 0xFF;
+    }
 
-    header_writer.write::<8, u8>(payload.len() as u8)?;
+    header_writer.write::<8, u8>(payload_size as u8)?;
 
     let mut data = header_writer.into_inner();
     data.extend_from_slice(payload);
@@ -78,3 +210,26 @@ pub fn encode_payload_to_sei_prefix(payload_type: u8, payload: &[u8]) -> Result<
 
     Ok(data)
 }
+
+#[cfg(test)]
+mod tests {
+    use hevc_parser::utils::clear_start_code_emulation_prevention_3_byte;
+
+    use super::*;
+
+    #[test]
+    fn test_encode_payload_size() {
+        // payload_size is coded with 0xFF bytes from 255 bytes, without a size limit
+        for size in [0, 254, 255, 256, 510, 1024, 5000] {
+            let payload = vec![0x11; size];
+            let data = encode_payload_to_sei_prefix(5, &payload).unwrap();
+
+            let sei_payload = clear_start_code_emulation_prevention_3_byte(&data);
+            let messages = SeiMessage::parse_sei_rbsp(&sei_payload).unwrap();
+
+            assert_eq!(messages.len(), 1);
+            assert_eq!(messages[0].payload_type, 5);
+            assert_eq!(sei_message_data(&messages[0], &sei_payload), payload);
+        }
+    }
+}
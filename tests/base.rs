@@ -1,9 +1,92 @@
 use std::path::Path;
 
 use anyhow::Result;
-use assert_cmd::cargo;
+use assert_cmd::{Command, cargo};
+use assert_fs::TempDir;
+use assert_fs::fixture::ChildPath;
 use assert_fs::prelude::*;
 use predicates::prelude::*;
+use serde_json::{Value, json};
+
+use hevc_hdr_editor::{
+    CllTransform, EditCllMetadata, EditConfig, EditMdcvMetadata, MdcvPrimariesPreset, NalFraming,
+    Processor,
+};
+
+/// CLI command editing the input with the config file
+fn edit_cmd(
+    input: impl AsRef<Path>,
+    config_file: impl AsRef<Path>,
+    output: impl AsRef<Path>,
+) -> Command {
+    let mut cmd = cargo::cargo_bin_cmd!();
+    cmd.arg("--input")
+        .arg(input.as_ref())
+        .arg("--config")
+        .arg(config_file.as_ref())
+        .arg("--output")
+        .arg(output.as_ref());
+
+    cmd
+}
+
+/// Edits the input with the CLI, expecting nothing on stderr, and returns the output
+fn run_edit(config: &Value, input: impl AsRef<Path>) -> Result<Vec<u8>> {
+    run_edit_with_args(config, input, &[])
+}
+
+fn run_edit_with_args(config: &Value, input: impl AsRef<Path>, args: &[&str]) -> Result<Vec<u8>> {
+    let temp = TempDir::new()?;
+    let config_file = write_config(&temp, "config.json", config)?;
+    let output_file = temp.child("output.hevc");
+
+    edit_cmd(input, &config_file, &output_file)
+        .args(args)
+        .assert()
+        .success()
+        .stderr(predicate::str::is_empty());
+
+    Ok(std::fs::read(output_file)?)
+}
+
+fn write_config(temp: &TempDir, name: &str, config: &Value) -> Result<ChildPath> {
+    let config_file = temp.child(name);
+    config_file.write_str(&config.to_string())?;
+
+    Ok(config_file)
+}
+
+/// Writes the data as an input file, the format being detected from the name
+fn write_input(temp: &TempDir, name: &str, data: &[u8]) -> Result<ChildPath> {
+    let input_file = temp.child(name);
+    input_file.write_binary(data)?;
+
+    Ok(input_file)
+}
+
+fn read(path: impl AsRef<Path>) -> Result<Vec<u8>> {
+    Ok(std::fs::read(path)?)
+}
+
+fn example_config() -> Result<Value> {
+    Ok(serde_json::from_slice(&read(
+        "assets/example_config.json",
+    )?)?)
+}
+
+/// Compares the outputs without printing whole files on failure
+fn assert_same_output(output: &[u8], expected: &[u8]) {
+    if let Some(offset) = output.iter().zip(expected).position(|(a, b)| a != b) {
+        panic!("Outputs differ at offset {offset}");
+    }
+    assert_eq!(output.len(), expected.len(), "Outputs differ in length");
+}
+
+fn count_matches(data: &[u8], pattern: &[u8]) -> usize {
+    data.windows(pattern.len())
+        .filter(|window| *window == pattern)
+        .count()
+}
 
 #[test]
 fn help() -> Result<()> {
@@ -14,7 +97,7 @@ fn help() -> Result<()> {
         .success()
         .stderr(predicate::str::is_empty())
         .stdout(predicate::str::contains(
-            "hevc_hdr_editor [OPTIONS] --config <CONFIG> [input_pos]",
+            "hevc_hdr_editor [OPTIONS] [input_pos]",
         ));
     Ok(())
 }
@@ -190,3 +273,1597 @@ fn edit_partial_config_multimsg() -> Result<()> {
 
     Ok(())
 }
+
+#[test]
+fn edit_unrelated_config_multimsg() -> Result<()> {
+    // no configured payload type in the SEI, which isn't split
+    for config in [
+        json!({ "atc": { "remove": true } }),
+        json!({ "ave": { "remove": true } }),
+        json!({ "ccv": { "remove": true } }),
+    ] {
+        let output = run_edit(&config, "assets/multimsg-sei.hevc")?;
+        assert_same_output(&output, &read("assets/multimsg-sei.hevc")?);
+    }
+
+    Ok(())
+}
+
+#[test]
+fn edit_match_condition() -> Result<()> {
+    // same as example config, only editing when the existing metadata matches
+    let config = json!({
+        "mdcv": {
+            "match": {
+                "preset": "BT2020",
+                "max_display_mastering_luminance": 1000
+            },
+            "preset": "DisplayP3",
+            "max_display_mastering_luminance": 4000,
+            "min_display_mastering_luminance": 0.0050
+        },
+        "cll": {
+            "match": {
+                "max_content_light_level": 1000,
+                "max_frame_average_light_level": 400
+            },
+            "max_content_light_level": 2800,
+            "max_frame_average_light_level": 225
+        }
+    });
+
+    let output = run_edit(&config, "assets/regular.hevc")?;
+    assert_same_output(&output, &read("assets/regular_example_cfg.hevc")?);
+
+    Ok(())
+}
+
+#[test]
+fn edit_match_condition_mismatch() -> Result<()> {
+    // existing metadata doesn't match, left untouched
+    let config = json!({
+        "mdcv": {
+            "match": {
+                "max_display_mastering_luminance": 10000
+            },
+            "max_display_mastering_luminance": 4000
+        },
+        "cll": {
+            "match": {
+                "max_content_light_level": 0,
+                "max_frame_average_light_level": 0
+            },
+            "max_content_light_level": 2800,
+            "max_frame_average_light_level": 225
+        }
+    });
+
+    let output = run_edit(&config, "assets/regular.hevc")?;
+    assert_same_output(&output, &read("assets/regular.hevc")?);
+
+    Ok(())
+}
+
+#[test]
+fn edit_transforms() -> Result<()> {
+    // results in the same values as the example config
+    let config = json!({
+        "mdcv": {
+            "preset": "DisplayP3",
+            "min_display_mastering_luminance": 0.0012,
+            "transforms": [
+                { "scale_luminance": 4 },
+                "round_min_luminance"
+            ]
+        },
+        "cll": {
+            "max_content_light_level": 5600,
+            "max_frame_average_light_level": 450,
+            "transforms": [
+                { "scale": 0.5 }
+            ]
+        }
+    });
+
+    let output = run_edit(&config, "assets/regular.hevc")?;
+    assert_same_output(&output, &read("assets/regular_example_cfg.hevc")?);
+
+    Ok(())
+}
+
+#[test]
+fn edit_transform_clamp_to_mdcv() -> Result<()> {
+    // clamped back to the 1000 nits MDCV, which comes after the CLL SEI
+    let config = json!({
+        "cll": {
+            "max_content_light_level": 4000,
+            "transforms": [
+                "clamp_to_mdcv",
+                "limit_max_fall"
+            ]
+        }
+    });
+
+    let output = run_edit(&config, "assets/regular.hevc")?;
+    assert_same_output(&output, &read("assets/regular.hevc")?);
+
+    Ok(())
+}
+
+#[test]
+fn edit_transform_clamp_to_mdcv_missing() -> Result<()> {
+    let temp = TempDir::new()?;
+    let config_file = write_config(
+        &temp,
+        "config.json",
+        &json!({
+            "sei_rules": [{ "payload_type": 137, "action": "drop" }],
+            "cll": {
+                "max_content_light_level": 4000,
+                "transforms": ["clamp_to_mdcv"]
+            }
+        }),
+    )?;
+    let output_file = temp.child("output.hevc");
+
+    // warned once, the CLL is left unclamped
+    let assert = edit_cmd("assets/regular.hevc", &config_file, &output_file)
+        .assert()
+        .success();
+    let stderr = String::from_utf8(assert.get_output().stderr.clone())?;
+    assert_eq!(
+        stderr.lines().collect::<Vec<_>>(),
+        ["WARN  No MDCV metadata, the edits requiring it are skipped offset=109 access_unit=0"]
+    );
+
+    let expected = run_edit(
+        &json!({
+            "sei_rules": [{ "payload_type": 137, "action": "drop" }],
+            "cll": { "max_content_light_level": 4000 }
+        }),
+        "assets/regular.hevc",
+    )?;
+    assert_same_output(&read(&output_file)?, &expected);
+
+    Ok(())
+}
+
+#[test]
+fn edit_cll_from_hdr10plus() -> Result<()> {
+    let input_file = Path::new("assets/multimsg-sei.hevc");
+
+    let output = run_edit(&json!({ "cll": { "source": "hdr10plus" } }), input_file)?;
+
+    // MaxSCL 776.8 nits, average MaxRGB 26.3 nits
+    let expected_config = json!({
+        "cll": {
+            "max_content_light_level": 777,
+            "max_frame_average_light_level": 26
+        }
+    });
+    let expected = run_edit(&expected_config, input_file)?;
+
+    assert_same_output(&output, &expected);
+
+    Ok(())
+}
+
+/// Profile 8.1 RPU NAL, with L1 max 2081 PQ (100 nits) and avg 1229 PQ (10 nits)
+const PROFILE81_RPU_HEX: &[&str] = &[
+    "7c0119080908406136506f003ff801ffc00fffd0000008000006800000400000",
+    "340000030200000301a000000300000300000300000300000300000300000300",
+    "0003000003000003000003000003000003000003000003000003000003000003",
+    "000003000003000003000003000003000ffff000000300000300000300060007",
+    "d80e1530300800410a668090603e8000103e801900b691b2ff80",
+];
+
+/// Same RPU without L6 metadata
+const PROFILE81_NO_L6_RPU_HEX: &[&str] = &[
+    "7c0119080908406136506f003ff801ffc00fffd0000008000006800000400000",
+    "340000030200000301a000000300000300000300000300000300000300000300",
+    "0003000003000003000003000003000003000003000003000003000003000003",
+    "000003000003000003000003000003000ffff000000300000300000300060007",
+    "d80e1520300800410a668036d5e3e680",
+];
+
+fn rpu_nal(rpu_hex: &[&str]) -> Result<Vec<u8>> {
+    let rpu_hex = rpu_hex.concat();
+
+    Ok((0..rpu_hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&rpu_hex[i..i + 2], 16))
+        .collect::<Result<Vec<_>, _>>()?)
+}
+
+fn profile81_rpu_nal() -> Result<Vec<u8>> {
+    rpu_nal(PROFILE81_RPU_HEX)
+}
+
+/// Same RPU, with a CRC32 mismatch
+fn corrupted_rpu_nal() -> Result<Vec<u8>> {
+    let mut rpu_nal = profile81_rpu_nal()?;
+    let crc_offset = rpu_nal.len() - 2;
+    rpu_nal[crc_offset] ^= 0xFF;
+
+    Ok(rpu_nal)
+}
+
+/// Input with the RPU NALs appended after the last access unit
+fn input_with_rpus(temp: &TempDir, rpu_nals: &[Vec<u8>]) -> Result<ChildPath> {
+    let mut data = read("assets/regular.hevc")?;
+
+    for rpu_nal in rpu_nals {
+        data.extend_from_slice(&[0, 0, 0, 1]);
+        data.extend_from_slice(rpu_nal);
+    }
+
+    write_input(temp, "input_rpu.hevc", &data)
+}
+
+fn input_with_rpu(temp: &TempDir) -> Result<ChildPath> {
+    input_with_rpus(temp, &[profile81_rpu_nal()?])
+}
+
+#[test]
+fn edit_cll_from_dolby_vision() -> Result<()> {
+    let temp = TempDir::new()?;
+    let input_file = input_with_rpu(&temp)?;
+
+    let output = run_edit(&json!({ "cll": { "source": "dolby_vision" } }), &input_file)?;
+
+    let expected_config = json!({
+        "cll": {
+            "max_content_light_level": 100,
+            "max_frame_average_light_level": 10
+        }
+    });
+    let expected = run_edit(&expected_config, &input_file)?;
+
+    assert_same_output(&output, &expected);
+
+    Ok(())
+}
+
+#[test]
+fn edit_cll_from_dolby_vision_unsupported_rpu() -> Result<()> {
+    let temp = TempDir::new()?;
+    let input_file = input_with_rpus(&temp, &[corrupted_rpu_nal()?, profile81_rpu_nal()?])?;
+    let config_file = write_config(
+        &temp,
+        "config.json",
+        &json!({ "cll": { "source": "dolby_vision" } }),
+    )?;
+    let output_file = temp.child("output.hevc");
+
+    // Computed from the supported RPU only
+    edit_cmd(&input_file, &config_file, &output_file)
+        .assert()
+        .success()
+        .stderr(predicate::str::contains(
+            "WARN  Skipped unsupported Dolby Vision RPUs while scanning count=1",
+        ));
+
+    let expected_config = json!({
+        "cll": {
+            "max_content_light_level": 100,
+            "max_frame_average_light_level": 10
+        }
+    });
+    assert_same_output(
+        &read(&output_file)?,
+        &run_edit(&expected_config, &input_file)?,
+    );
+
+    Ok(())
+}
+
+#[test]
+fn dolby_vision_l6_to_hdr10() -> Result<()> {
+    let temp = TempDir::new()?;
+    let input_file = input_with_rpu(&temp)?;
+
+    // L6 is the same as the existing HDR10 metadata
+    let output = run_edit(
+        &json!({ "dolby_vision": { "l6": "to_hdr10" } }),
+        &input_file,
+    )?;
+    assert_same_output(&output, &read(&input_file)?);
+
+    // Values set in the config are kept
+    let cll_config = json!({ "cll": { "max_content_light_level": 2000 } });
+    let mut config = cll_config.clone();
+    config["dolby_vision"] = json!({ "l6": "to_hdr10" });
+
+    let output = run_edit(&config, &input_file)?;
+    assert_same_output(&output, &run_edit(&cll_config, &input_file)?);
+
+    Ok(())
+}
+
+#[test]
+fn dolby_vision_l6_from_hdr10() -> Result<()> {
+    let temp = TempDir::new()?;
+    let input_file = input_with_rpu(&temp)?;
+
+    let mut config = example_config()?;
+    config["dolby_vision"] = json!({ "l6": "from_hdr10" });
+
+    let output = run_edit(&config, &input_file)?;
+    let output_file = write_input(&temp, "output.hevc", &output)?;
+
+    // Copying the synced L6 back results in the same HDR10 metadata
+    let roundtrip = run_edit(
+        &json!({ "dolby_vision": { "l6": "to_hdr10" } }),
+        &output_file,
+    )?;
+    assert_same_output(&roundtrip, &output);
+
+    Ok(())
+}
+
+#[test]
+fn dolby_vision_l6_unsupported_rpu() -> Result<()> {
+    let temp = TempDir::new()?;
+    let input_file = input_with_rpus(
+        &temp,
+        &[corrupted_rpu_nal()?, rpu_nal(PROFILE81_NO_L6_RPU_HEX)?],
+    )?;
+    let config_file = write_config(
+        &temp,
+        "config.json",
+        &json!({ "dolby_vision": { "l6": "from_hdr10" } }),
+    )?;
+    let output_file = temp.child("output.hevc");
+
+    edit_cmd(&input_file, &config_file, &output_file)
+        .assert()
+        .success()
+        .stderr(predicate::str::contains(
+            "WARN  Passing through unsupported Dolby Vision RPU",
+        ))
+        .stderr(predicate::str::contains(
+            "WARN  Passing through Dolby Vision RPU without L6 metadata",
+        ))
+        .stdout(predicate::str::contains(
+            "Passed through 2 unsynced Dolby Vision RPUs",
+        ));
+
+    assert_same_output(&read(&output_file)?, &read(&input_file)?);
+
+    Ok(())
+}
+
+#[test]
+fn dolby_vision_remove() -> Result<()> {
+    let temp = TempDir::new()?;
+    let input_file = input_with_rpu(&temp)?;
+    let config_file = write_config(
+        &temp,
+        "config.json",
+        &json!({ "dolby_vision": { "remove": true } }),
+    )?;
+    let output_file = temp.child("output.hevc");
+
+    edit_cmd(&input_file, &config_file, &output_file)
+        .assert()
+        .success()
+        .stderr(predicate::str::is_empty())
+        .stdout(predicate::str::contains("Removed 1 Dolby Vision NAL units"));
+
+    assert_same_output(&read(&output_file)?, &read("assets/regular.hevc")?);
+
+    Ok(())
+}
+
+#[test]
+fn edit_vui_roundtrip() -> Result<()> {
+    let temp = TempDir::new()?;
+    let input = read("assets/regular.hevc")?;
+
+    let bt709_config = json!({
+        "vui": {
+            "colour_primaries": 1,
+            "transfer_characteristics": 1,
+            "matrix_coeffs": 1
+        }
+    });
+    let bt2020_config = json!({
+        "vui": {
+            "video_full_range_flag": false,
+            "colour_primaries": 9,
+            "transfer_characteristics": 16,
+            "matrix_coeffs": 9
+        }
+    });
+
+    let bt709 = run_edit(&bt709_config, "assets/regular.hevc")?;
+    assert!(bt709 != input);
+
+    let bt709_file = write_input(&temp, "bt709.hevc", &bt709)?;
+    let output = run_edit(&bt2020_config, &bt709_file)?;
+    assert_same_output(&output, &input);
+
+    Ok(())
+}
+
+#[test]
+fn edit_atc_insert_remove() -> Result<()> {
+    let temp = TempDir::new()?;
+    let input = read("assets/regular.hevc")?;
+
+    let insert_config = json!({
+        "atc": {
+            "preferred_transfer_characteristics": 18,
+            "insert": true
+        }
+    });
+    let inserted = run_edit(&insert_config, "assets/regular.hevc")?;
+
+    // SEI prefix NAL with ATC payload, HLG preferred
+    let atc_nal: &[u8] = &[0, 0, 0, 1, 0x4E, 0x01, 147, 1, 18, 0x80];
+    let sps_header: &[u8] = &[0, 0, 1, 0x42, 0x01];
+
+    // Inserted once per access unit with a SPS
+    let sps_count = count_matches(&input, sps_header);
+    assert!(sps_count > 0);
+    assert_eq!(count_matches(&inserted, atc_nal), sps_count);
+
+    let inserted_file = write_input(&temp, "inserted.hevc", &inserted)?;
+    let output = run_edit(&json!({ "atc": { "remove": true } }), &inserted_file)?;
+    assert_same_output(&output, &input);
+
+    Ok(())
+}
+
+#[test]
+fn edit_ave_insert_edit_remove() -> Result<()> {
+    let temp = TempDir::new()?;
+    let input = read("assets/regular.hevc")?;
+
+    let insert_config = json!({
+        "ave": {
+            "ambient_illuminance": 3140000,
+            "ambient_light_x": 15635,
+            "ambient_light_y": 16450,
+            "insert": true
+        }
+    });
+    let inserted = run_edit(&insert_config, "assets/regular.hevc")?;
+    let inserted_file = write_input(&temp, "inserted.hevc", &inserted)?;
+
+    let edited = run_edit(
+        &json!({ "ave": { "ambient_illuminance": 50000 } }),
+        &inserted_file,
+    )?;
+    let edited_file = write_input(&temp, "edited.hevc", &edited)?;
+
+    // SEI prefix NAL with AVE payload: 314 lux, D65
+    let inserted_nal: &[u8] = &[
+        0, 0, 0, 1, 0x4E, 0x01, 148, 8, 0x00, 0x2F, 0xE9, 0xA0, 0x3D, 0x13, 0x40, 0x42, 0x80,
+    ];
+    // 5 lux
+    let edited_nal: &[u8] = &[
+        0, 0, 0, 1, 0x4E, 0x01, 148, 8, 0x00, 0x00, 0xC3, 0x50, 0x3D, 0x13, 0x40, 0x42, 0x80,
+    ];
+
+    let sps_count = count_matches(&input, &[0, 0, 1, 0x42, 0x01]);
+    assert!(sps_count > 0);
+    assert_eq!(count_matches(&inserted, inserted_nal), sps_count);
+    assert_eq!(count_matches(&edited, edited_nal), sps_count);
+
+    let output = run_edit(&json!({ "ave": { "remove": true } }), &edited_file)?;
+    assert_same_output(&output, &input);
+
+    Ok(())
+}
+
+#[test]
+fn edit_ccv_insert_edit_remove() -> Result<()> {
+    let temp = TempDir::new()?;
+    let input = read("assets/regular.hevc")?;
+
+    let insert_config = json!({
+        "ccv": {
+            "preset": "BT2020",
+            "min_luminance_value": 0.005,
+            "max_luminance_value": 400,
+            "avg_luminance_value": 100,
+            "insert": true
+        }
+    });
+    let inserted = run_edit(&insert_config, "assets/regular.hevc")?;
+    let inserted_file = write_input(&temp, "inserted.hevc", &inserted)?;
+
+    let edited = run_edit(
+        &json!({ "ccv": { "max_luminance_value": 300 } }),
+        &inserted_file,
+    )?;
+    let edited_file = write_input(&temp, "edited.hevc", &edited)?;
+
+    // SEI prefix NAL header and CCV payload with BT.2020 primaries, min 0.005 nits
+    let ccv_nal: &[u8] = &[
+        0, 0, 0, 1, 0x4E, 0x01, 149, 37, 0x7C, 0x00, 0x00, 0x21, 0x34, 0x00, 0x00, 0x9B, 0xAA,
+        0x00, 0x00, 0x19, 0x96, 0x00, 0x00, 0x08, 0xFC, 0x00, 0x00, 0x8A, 0x48, 0x00, 0x00, 0x39,
+        0x08, 0x00, 0x00, 0xC3, 0x50,
+    ];
+    // max 400, avg 100 nits
+    let inserted_luminance: &[u8] = &[0xEE, 0x6B, 0x28, 0x00, 0x3B, 0x9A, 0xCA, 0x00, 0x80];
+    // max 300, avg 100 nits
+    let edited_luminance: &[u8] = &[0xB2, 0xD0, 0x5E, 0x00, 0x3B, 0x9A, 0xCA, 0x00, 0x80];
+
+    let sps_count = count_matches(&input, &[0, 0, 1, 0x42, 0x01]);
+    assert!(sps_count > 0);
+    assert_eq!(
+        count_matches(&inserted, &[ccv_nal, inserted_luminance].concat()),
+        sps_count
+    );
+    assert_eq!(
+        count_matches(&edited, &[ccv_nal, edited_luminance].concat()),
+        sps_count
+    );
+
+    let output = run_edit(&json!({ "ccv": { "remove": true } }), &edited_file)?;
+    assert_same_output(&output, &input);
+
+    Ok(())
+}
+
+#[test]
+fn edit_hdr10plus_tsdml_from_mdcv() -> Result<()> {
+    let temp = TempDir::new()?;
+    let input_file = Path::new("assets/multimsg-sei.hevc");
+
+    let output = run_edit(
+        &json!({ "hdr10plus": { "tsdml_from_mdcv": true } }),
+        input_file,
+    )?;
+
+    // MDCV max luminance is 1000 nits
+    let expected_config = json!({
+        "hdr10plus": {
+            "targeted_system_display_maximum_luminance": 1000
+        }
+    });
+    let expected = run_edit(&expected_config, input_file)?;
+
+    // Same values as the original metadata
+    let noop_config = json!({
+        "hdr10plus": {
+            "application_version": 1,
+            "targeted_system_display_maximum_luminance": 400
+        }
+    });
+    let noop = run_edit(&noop_config, input_file)?;
+
+    assert_same_output(&output, &expected);
+    assert!(output != noop);
+
+    // Re-encoding the unedited values is lossless
+    let expected_file = write_input(&temp, "expected.hevc", &expected)?;
+    let roundtrip = run_edit(&noop_config, &expected_file)?;
+    assert_same_output(&roundtrip, &noop);
+
+    Ok(())
+}
+
+#[test]
+fn edit_hdr10plus_remove() -> Result<()> {
+    let temp = TempDir::new()?;
+
+    let output = run_edit(
+        &json!({ "hdr10plus": { "remove": true } }),
+        "assets/multimsg-sei.hevc",
+    )?;
+    let output_file = write_input(&temp, "output.hevc", &output)?;
+    let scan_config = write_config(
+        &temp,
+        "scan_config.json",
+        &json!({ "cll": { "source": "hdr10plus" } }),
+    )?;
+
+    // No HDR10+ metadata left to compute the CLL from
+    edit_cmd(&output_file, &scan_config, temp.child("scanned.hevc"))
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains("No HDR10+ metadata found"));
+
+    Ok(())
+}
+
+/// Samsung HDR10+ JSON, with the frame's presentation index as targeted display luminance offset
+fn hdr10plus_json(frames: usize) -> String {
+    let scene_info = (0..frames)
+        .map(|i| {
+            json!({
+                "BezierCurveData": {
+                    "Anchors": [102, 205, 307, 410, 512, 614, 717, 819, 922],
+                    "KneePointX": 0,
+                    "KneePointY": 0
+                },
+                "LuminanceParameters": {
+                    "AverageRGB": 1000,
+                    "LuminanceDistributions": {
+                        "DistributionIndex": [1, 5, 10, 25, 50, 75, 90, 95, 99],
+                        "DistributionValues": [0, 10, 100, 500, 1000, 2000, 4000, 5000, 6000]
+                    },
+                    "MaxScl": [i * 10, 5000, 4000]
+                },
+                "NumberOfWindows": 1,
+                "TargetedSystemDisplayMaximumLuminance": 1000 + i,
+                "SceneFrameIndex": i,
+                "SceneId": 0,
+                "SequenceFrameIndex": i
+            })
+        })
+        .collect::<Vec<_>>();
+
+    json!({
+        "JSONInfo": {
+            "HDR10plusProfile": "B",
+            "Version": "1.0"
+        },
+        "SceneInfo": scene_info
+    })
+    .to_string()
+}
+
+#[test]
+fn inject_hdr10plus() -> Result<()> {
+    let temp = TempDir::new()?;
+
+    // 259 frames in the input
+    let metadata_file = temp.child("metadata.json");
+    metadata_file.write_str(&hdr10plus_json(259))?;
+
+    let output = run_edit(
+        &json!({ "hdr10plus": { "inject": metadata_file.path() } }),
+        "assets/regular.hevc",
+    )?;
+
+    // SEI prefix NAL header, T.35 payload type and HDR10+ header
+    let header: &[u8] = &[0x4E, 0x01, 0x04];
+    let t35_header: &[u8] = &[0xB5, 0x00, 0x3C, 0x00, 0x01, 0x04, 0x01];
+
+    let presentation_indices = output
+        .windows(header.len() + 1 + t35_header.len() + 4)
+        .filter(|w| w.starts_with(header) && w[header.len() + 1..].starts_with(t35_header))
+        .map(|w| {
+            // num_windows (2 bits), then targeted_system_display_maximum_luminance (27 bits)
+            let bytes = &w[header.len() + 1 + t35_header.len()..];
+            let value = u32::from_be_bytes(bytes.try_into().unwrap());
+
+            ((value >> 3) & 0x7FF_FFFF) - 1000
+        })
+        .collect::<Vec<_>>();
+
+    // Every frame got metadata, in decode order
+    let mut sorted = presentation_indices.clone();
+    sorted.sort_unstable();
+
+    assert_eq!(sorted, (0..259).collect::<Vec<_>>());
+    assert_eq!(presentation_indices[0], 0);
+    assert_ne!(presentation_indices, sorted);
+
+    Ok(())
+}
+
+#[test]
+fn inject_hdr10plus_frame_count_mismatch() -> Result<()> {
+    let temp = TempDir::new()?;
+
+    let metadata_file = temp.child("metadata.json");
+    metadata_file.write_str(&hdr10plus_json(100))?;
+    let config_file = write_config(
+        &temp,
+        "config.json",
+        &json!({ "hdr10plus": { "inject": metadata_file.path() } }),
+    )?;
+
+    edit_cmd(
+        "assets/regular.hevc",
+        &config_file,
+        temp.child("output.hevc"),
+    )
+    .assert()
+    .failure()
+    .stderr(predicate::str::contains(
+        "HDR10+ JSON metadata has 100 frames, but the input has 259 frames",
+    ));
+
+    Ok(())
+}
+
+#[test]
+fn remove_user_data_unregistered() -> Result<()> {
+    let input_file = Path::new("assets/regular.hevc");
+    let input = read(input_file)?;
+
+    // x265 encoder information
+    let x265_config = json!({
+        "udu": {
+            "remove": true,
+            "uuids": ["2ca2de09-b517-47db-bb55-a4fe7fc2fc4e"]
+        }
+    });
+    let mismatch_config = json!({
+        "udu": {
+            "remove": true,
+            "uuids": ["00000000000000000000000000000000"]
+        }
+    });
+
+    let x265 = run_edit(&x265_config, input_file)?;
+    let all = run_edit(&json!({ "udu": { "remove": true } }), input_file)?;
+    let mismatch = run_edit(&mismatch_config, input_file)?;
+
+    assert!(count_matches(&input, b"x265") > 0);
+    assert_eq!(count_matches(&x265, b"x265"), 0);
+
+    // Only one message in the input
+    assert_same_output(&all, &x265);
+
+    // Unmatched messages are left untouched
+    assert_same_output(&mismatch, &input);
+
+    Ok(())
+}
+
+#[test]
+fn sei_rules() -> Result<()> {
+    let input_file = Path::new("assets/regular.hevc");
+
+    // Each rules config is expected to produce the same output as the typed config
+    let cases = [
+        (
+            json!({
+                "sei_rules": [{ "payload_type": 5, "action": "drop" }]
+            }),
+            json!({
+                "udu": { "remove": true }
+            }),
+        ),
+        (
+            json!({
+                "sei_rules": [{ "payload_type": 147, "action": { "insert": { "hex": "12" } } }]
+            }),
+            json!({
+                "atc": { "preferred_transfer_characteristics": 18, "insert": true }
+            }),
+        ),
+        (
+            // MaxCLL 2000, MaxFALL 400, with the MaxFALL edited on top
+            json!({
+                "sei_rules": [{ "payload_type": 144, "action": { "replace": { "base64": "B9ABkA==" } } }],
+                "cll": { "max_frame_average_light_level": 500 }
+            }),
+            json!({
+                "cll": { "max_content_light_level": 2000, "max_frame_average_light_level": 500 }
+            }),
+        ),
+        (
+            // The first rule wins, and the typed edits are ignored
+            json!({
+                "sei_rules": [
+                    { "payload_type": 5, "action": "keep" },
+                    { "payload_type": 5, "action": "drop" }
+                ],
+                "udu": { "remove": true }
+            }),
+            json!({
+                "udu": { "remove": true, "uuids": ["00000000000000000000000000000000"] }
+            }),
+        ),
+    ];
+
+    for (rules, typed) in cases {
+        assert_same_output(
+            &run_edit(&rules, input_file)?,
+            &run_edit(&typed, input_file)?,
+        );
+    }
+
+    Ok(())
+}
+
+#[test]
+fn provenance_insert_revert() -> Result<()> {
+    let temp = TempDir::new()?;
+
+    let mdcv_config = json!({
+        "mdcv": { "max_display_mastering_luminance": 4000 },
+        "provenance": { "insert": true }
+    });
+    let cll_config = json!({
+        "mdcv": { "max_display_mastering_luminance": 2000 },
+        "cll": { "max_content_light_level": 2000 },
+        "provenance": { "insert": true }
+    });
+
+    // Edited twice, the records keep the values of the input
+    let mdcv = run_edit(&mdcv_config, "assets/regular.hevc")?;
+    let mdcv_file = write_input(&temp, "mdcv.hevc", &mdcv)?;
+    let edited = run_edit(&cll_config, &mdcv_file)?;
+    let edited_file = write_input(&temp, "cll.hevc", &edited)?;
+
+    let uuid: &[u8] = &[
+        0x84, 0x28, 0x56, 0xE4, 0xBB, 0x92, 0x43, 0xEA, 0xB8, 0x99, 0x8D, 0x8C, 0xF3, 0x18, 0xEE,
+        0x06,
+    ];
+
+    assert!(count_matches(&edited, uuid) > 0);
+    assert!(count_matches(&edited, br#""tool":"hevc_hdr_editor""#) > 0);
+    assert!(
+        count_matches(
+            &edited,
+            br#""cll":{"original":{"max_content_light_level":1000,"max_frame_average_light_level":400}"#
+        ) > 0
+    );
+
+    // Kept without insert or revert
+    let output = run_edit(
+        &json!({ "cll": { "max_content_light_level": 1000 }, "provenance": {} }),
+        &edited_file,
+    )?;
+    assert!(count_matches(&output, uuid) > 0);
+
+    let output = run_edit(&json!({ "provenance": { "revert": true } }), &edited_file)?;
+    assert_same_output(&output, &read("assets/regular.hevc")?);
+
+    Ok(())
+}
+
+#[test]
+fn library_edit_reader() -> Result<()> {
+    let input_file = Path::new("assets/regular.hevc");
+
+    let config = json!({
+        "mdcv": { "preset": "DisplayP3", "max_display_mastering_luminance": 4000 },
+        "cll": { "max_content_light_level": 2000, "max_frame_average_light_level": 500 }
+    });
+    let expected = run_edit(&config, input_file)?;
+
+    let mut processor = Processor::new(serde_json::from_value(config)?, Vec::new())?;
+    processor.edit_reader(std::fs::File::open(input_file)?)?;
+
+    // Same output as the CLI
+    assert_same_output(&processor.into_writer(), &expected);
+
+    // Scanning the input is only possible with files
+    let config: EditConfig = serde_json::from_value(json!({
+        "cll": { "source": "hdr10plus" }
+    }))?;
+    let mut processor = Processor::new(config, Vec::new())?;
+    let err = processor.edit_reader(std::fs::File::open(input_file)?);
+    assert!(err.is_err());
+
+    Ok(())
+}
+
+#[test]
+fn library_config() -> Result<()> {
+    let input_file = Path::new("assets/regular.hevc");
+
+    let expected = run_edit(
+        &json!({
+            "mdcv": { "preset": "BT709", "max_display_mastering_luminance": 4000 },
+            "cll": { "max_content_light_level": 2000, "transforms": ["limit_max_fall"] }
+        }),
+        input_file,
+    )?;
+
+    // Same config built without JSON
+    let config = EditConfig {
+        mdcv: Some(EditMdcvMetadata {
+            preset: Some(MdcvPrimariesPreset::BT709),
+            max_display_mastering_luminance: Some(4000.0),
+            ..Default::default()
+        }),
+        cll: Some(EditCllMetadata {
+            max_content_light_level: Some(2000),
+            transforms: vec![CllTransform::LimitMaxFall],
+            ..Default::default()
+        }),
+        ..Default::default()
+    };
+    let input = read(input_file)?;
+
+    let edited = Processor::edit_buffer(&config, &input, NalFraming::AnnexB)?;
+    assert_same_output(&edited, &expected);
+
+    Ok(())
+}
+
+#[test]
+fn library_edit_buffer() -> Result<()> {
+    let input_file = Path::new("assets/regular.hevc");
+
+    let config = json!({
+        "mdcv": { "max_display_mastering_luminance": 4000 },
+        "cll": { "max_content_light_level": 2000 }
+    });
+    let expected = run_edit(&config, input_file)?;
+
+    let config: EditConfig = serde_json::from_value(config)?;
+    let input = read(input_file)?;
+
+    let edited = Processor::edit_buffer(&config, &input, NalFraming::AnnexB)?;
+    assert_same_output(&edited, &expected);
+
+    // Start codes replaced by 4 bytes sizes
+    let length_prefixed = |data: &[u8]| {
+        let mut offsets: Vec<_> = (0..data.len() - 2)
+            .filter(|&i| data[i..i + 3] == [0, 0, 1])
+            .map(|i| i + 3)
+            .collect();
+        offsets.push(data.len() + 3);
+
+        offsets
+            .windows(2)
+            .flat_map(|w| {
+                let mut nal = &data[w[0]..w[1] - 3];
+                while nal.last() == Some(&0) {
+                    nal = &nal[..nal.len() - 1];
+                }
+
+                [&(nal.len() as u32).to_be_bytes()[..], nal].concat()
+            })
+            .collect::<Vec<u8>>()
+    };
+
+    let edited = Processor::edit_buffer(
+        &config,
+        &length_prefixed(&input),
+        NalFraming::LengthPrefixed(4),
+    )?;
+    assert_same_output(&edited, &length_prefixed(&expected));
+
+    Ok(())
+}
+
+#[test]
+fn output_stdout() -> Result<()> {
+    let temp = TempDir::new()?;
+
+    let input_file = Path::new("assets/regular.hevc");
+    let config = json!({
+        "cll": { "max_content_light_level": 2000 }
+    });
+    let config_file = write_config(&temp, "config.json", &config)?;
+
+    // Piped from stdin to stdout
+    let assert = edit_cmd("-", &config_file, "-")
+        .pipe_stdin(input_file)?
+        .assert()
+        .success()
+        .stderr(predicate::str::is_empty());
+
+    assert_same_output(&assert.get_output().stdout, &run_edit(&config, input_file)?);
+
+    Ok(())
+}
+
+#[test]
+fn batch_manifest() -> Result<()> {
+    let temp = TempDir::new()?;
+
+    let input_file = Path::new("assets/regular.hevc").canonicalize()?;
+
+    let config = json!({
+        "cll": { "max_content_light_level": 2000, "max_frame_average_light_level": 500 }
+    });
+    let override_config = json!({
+        "cll": { "max_content_light_level": 3000, "max_frame_average_light_level": 500 }
+    });
+    let config_file = write_config(&temp, "config.json", &config)?;
+    write_config(&temp, "override.json", &override_config)?;
+
+    let expected = run_edit(&config, &input_file)?;
+    let expected_override = run_edit(&override_config, &input_file)?;
+
+    // Named from the default output template, next to the input
+    write_input(&temp, "S01E01.hevc", &read(&input_file)?)?;
+
+    // Relative to the manifest
+    let json_manifest = temp.child("manifest.json");
+    json_manifest.write_str(
+        &json!([
+            { "input": input_file, "output": "default.hevc" },
+            {
+                "input": input_file,
+                "output": "override.hevc",
+                "overrides": { "cll": { "max_content_light_level": 3000 } }
+            },
+            { "input": "missing.hevc", "output": "missing_out.hevc" },
+            { "input": "S01E01.hevc" }
+        ])
+        .to_string(),
+    )?;
+
+    let csv_manifest = temp.child("manifest.csv");
+    csv_manifest.write_str(&format!(
+        "input,output,config\n{0},csv_default.hevc,\n{0},csv_override.hevc,override.json\n",
+        input_file.display()
+    ))?;
+
+    let report_file = temp.child("report.json");
+
+    // Keeps going after the missing input
+    cargo::cargo_bin_cmd!()
+        .arg("--config")
+        .arg(config_file.as_ref())
+        .arg("--batch")
+        .arg(json_manifest.as_ref())
+        .arg("--jobs")
+        .arg("2")
+        .arg("--continue-on-error")
+        .arg("--report")
+        .arg(report_file.as_ref())
+        .assert()
+        .failure()
+        .stdout(predicate::str::contains(
+            "4 files: 3 succeeded, 1 failed, 0 skipped",
+        ))
+        .stderr(predicate::str::contains("1 of 4 files failed"));
+
+    let report: Value = serde_json::from_slice(&read(&report_file)?)?;
+    let statuses: Vec<_> = report["entries"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|entry| entry["status"].as_str().unwrap())
+        .collect();
+    assert_eq!(statuses, ["succeeded", "succeeded", "failed", "succeeded"]);
+
+    cargo::cargo_bin_cmd!()
+        .arg("--config")
+        .arg(config_file.as_ref())
+        .arg("--batch")
+        .arg(csv_manifest.as_ref())
+        .assert()
+        .success()
+        .stderr(predicate::str::is_empty());
+
+    // The config is only required for the entries without one
+    let config_manifest = temp.child("config_manifest.json");
+    config_manifest.write_str(
+        &json!([
+            { "input": input_file, "output": "entry_config.hevc", "config": "override.json" }
+        ])
+        .to_string(),
+    )?;
+
+    cargo::cargo_bin_cmd!()
+        .arg("--batch")
+        .arg(config_manifest.as_ref())
+        .assert()
+        .success()
+        .stderr(predicate::str::is_empty());
+
+    cargo::cargo_bin_cmd!()
+        .arg(&input_file)
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains("--config <CONFIG>"));
+
+    for (output, expected) in [
+        ("entry_config.hevc", &expected_override),
+        ("default.hevc", &expected),
+        ("override.hevc", &expected_override),
+        ("csv_default.hevc", &expected),
+        ("csv_override.hevc", &expected_override),
+        ("S01E01_hdr_edited.hevc", &expected),
+    ] {
+        assert_same_output(&read(temp.child(output))?, expected);
+    }
+
+    Ok(())
+}
+
+#[test]
+fn output_template() -> Result<()> {
+    let temp = TempDir::new()?;
+
+    let input_file = write_input(&temp, "S01E01.hevc", &read("assets/regular.hevc")?)?;
+
+    let config = json!({
+        "cll": { "max_content_light_level": 2000 }
+    });
+    let config_file = write_config(&temp, "config.json", &config)?;
+    let expected = run_edit(&config, &input_file)?;
+
+    cargo::cargo_bin_cmd!()
+        .arg("--input")
+        .arg(input_file.as_ref())
+        .arg("--config")
+        .arg(config_file.as_ref())
+        .arg("--output-dir")
+        .arg(temp.child("edited").as_ref())
+        .arg("--output-template")
+        .arg("{stem}.hdr10.hevc")
+        .assert()
+        .success()
+        .stderr(predicate::str::is_empty());
+
+    assert_same_output(&read(temp.child("edited/S01E01.hdr10.hevc"))?, &expected);
+
+    // Same file as the input, explicitly or from the template
+    edit_cmd(&input_file, &config_file, &input_file)
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains(
+            "Refusing to overwrite the input file",
+        ));
+
+    cargo::cargo_bin_cmd!()
+        .arg("--input")
+        .arg(input_file.as_ref())
+        .arg("--config")
+        .arg(config_file.as_ref())
+        .arg("--output-template")
+        .arg("{name}")
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains(
+            "Refusing to overwrite the input file",
+        ));
+
+    input_file.assert(predicate::path::eq_file(Path::new("assets/regular.hevc")));
+
+    Ok(())
+}
+
+#[cfg(unix)]
+#[test]
+fn output_permissions() -> Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+
+    let temp = TempDir::new()?;
+    let config_file = write_config(&temp, "config.json", &example_config()?)?;
+    let output_file = temp.child("output.hevc");
+
+    let mode =
+        |path: &Path| -> Result<u32> { Ok(std::fs::metadata(path)?.permissions().mode() & 0o777) };
+
+    // Same mode as any new file, from the umask
+    let reference_file = temp.child("reference");
+    std::fs::File::create(&reference_file)?;
+
+    edit_cmd("assets/regular.hevc", &config_file, &output_file)
+        .assert()
+        .success();
+    assert_eq!(mode(&output_file)?, mode(&reference_file)?);
+
+    // Replaced outputs keep their mode
+    std::fs::set_permissions(&output_file, std::fs::Permissions::from_mode(0o640))?;
+
+    edit_cmd("assets/regular.hevc", &config_file, &output_file)
+        .assert()
+        .success();
+    assert_eq!(mode(&output_file)?, 0o640);
+
+    Ok(())
+}
+
+#[cfg(unix)]
+#[test]
+fn interrupted_edit_removes_output() -> Result<()> {
+    use std::io::Write;
+    use std::process::Stdio;
+    use std::time::{Duration, Instant};
+
+    let temp = TempDir::new()?;
+    let config_file = write_config(&temp, "config.json", &example_config()?)?;
+    let output_file = temp.child("output.hevc");
+
+    let mut child = std::process::Command::new(cargo::cargo_bin!())
+        .arg("--config")
+        .arg(config_file.path())
+        .arg("--output")
+        .arg(output_file.path())
+        .arg("-")
+        .stdin(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()?;
+
+    let regular = read("assets/regular.hevc")?;
+    let mut stdin = child.stdin.take().unwrap();
+    stdin.write_all(&regular)?;
+
+    // The temporary output is created once the interrupt handler is set
+    let list_dir = || -> Result<Vec<String>> {
+        let mut names = std::fs::read_dir(temp.path())?
+            .map(|entry| Ok(entry?.file_name().to_string_lossy().into_owned()))
+            .collect::<Result<Vec<_>>>()?;
+        names.sort();
+        Ok(names)
+    };
+    let start = Instant::now();
+    while list_dir()?.len() < 2 {
+        assert!(
+            start.elapsed() < Duration::from_secs(30),
+            "No output created"
+        );
+        std::thread::sleep(Duration::from_millis(10));
+    }
+
+    // Pressing Ctrl-C twice still removes the temporary file
+    for _ in 0..2 {
+        let status = std::process::Command::new("kill")
+            .args(["-INT", &child.id().to_string()])
+            .status()?;
+        assert!(status.success());
+        std::thread::sleep(Duration::from_millis(50));
+    }
+
+    // Input following the interrupt stops the edit, which can close stdin early
+    let _ = stdin.write_all(&regular.repeat(10));
+    drop(stdin);
+
+    let output = child.wait_with_output()?;
+    assert!(!output.status.success());
+    assert!(String::from_utf8(output.stderr)?.contains("Interrupted"));
+    assert_eq!(list_dir()?, ["config.json"]);
+
+    Ok(())
+}
+
+#[test]
+fn failed_edit_keeps_output() -> Result<()> {
+    let temp = TempDir::new()?;
+
+    // Fails while parsing the first MDCV
+    let config_file = write_config(
+        &temp,
+        "config.json",
+        &json!({
+            "sei_rules": [{ "payload_type": 137, "action": { "replace": { "hex": "00" } } }],
+            "mdcv": { "max_display_mastering_luminance": 4000 }
+        }),
+    )?;
+    let output_file = temp.child("output.hevc");
+    output_file.write_str("previous output")?;
+
+    edit_cmd("assets/regular.hevc", &config_file, &output_file)
+        .assert()
+        .failure();
+
+    // No partial output or temporary file left
+    output_file.assert("previous output");
+
+    let mut files: Vec<_> = std::fs::read_dir(temp.path())?
+        .map(|entry| entry.map(|entry| entry.file_name()))
+        .collect::<std::io::Result<_>>()?;
+    files.sort();
+    assert_eq!(files, ["config.json", "output.hevc"]);
+
+    Ok(())
+}
+
+#[test]
+fn tolerant_malformed_sei() -> Result<()> {
+    let temp = TempDir::new()?;
+
+    // First MDCV NAL at offset 119, with a payload size larger than the NAL
+    let mut data = read("assets/regular.hevc")?;
+    assert_eq!(data[119..126], [0, 0, 1, 0x4E, 0x01, 0x89, 0x18]);
+    data[125] = 0x30;
+    let input_file = write_input(&temp, "malformed.hevc", &data)?;
+
+    let config_file = write_config(
+        &temp,
+        "config.json",
+        &json!({ "mdcv": { "max_display_mastering_luminance": 4000 } }),
+    )?;
+    let output_file = temp.child("output.hevc");
+
+    edit_cmd(&input_file, &config_file, &output_file)
+        .assert()
+        .failure();
+    output_file.assert(predicate::path::missing());
+
+    edit_cmd(&input_file, &config_file, &output_file)
+        .arg("--tolerant")
+        .assert()
+        .success()
+        .stderr(predicate::str::contains(
+            "WARN  Passing through malformed SEI NAL offset=122 access_unit=0",
+        ))
+        .stdout(predicate::str::contains(
+            "Passed through 1 malformed SEI NAL units",
+        ));
+
+    // The malformed NAL is written unchanged, the other MDCV is edited
+    let output = read(&output_file)?;
+    assert_eq!(count_matches(&output, &data[122..148]), 1);
+    assert_eq!(count_matches(&output, &[0x4E, 0x01, 0x89, 0x18]), 1);
+
+    Ok(())
+}
+
+#[test]
+fn tolerant_malformed_sei_offset() -> Result<()> {
+    let temp = TempDir::new()?;
+
+    // Malformed MDCV in a later copy of the input, past the first 100 KB chunk
+    let regular = read("assets/regular.hevc")?;
+    let mut data = regular.repeat(10);
+    let copy_offset = 7 * regular.len();
+    data[copy_offset + 125] = 0x30;
+    let input_file = write_input(&temp, "malformed.hevc", &data)?;
+
+    let config_file = write_config(
+        &temp,
+        "config.json",
+        &json!({ "mdcv": { "max_display_mastering_luminance": 4000 } }),
+    )?;
+    let output_file = temp.child("output.hevc");
+
+    let expected_offset = copy_offset + 122;
+    edit_cmd(&input_file, &config_file, &output_file)
+        .arg("--tolerant")
+        .assert()
+        .success()
+        .stderr(predicate::str::contains(format!(
+            "WARN  Passing through malformed SEI NAL offset={expected_offset} "
+        )));
+
+    // Same offset with the library, from the header of the malformed NAL
+    let config: EditConfig =
+        serde_json::from_value(json!({ "mdcv": { "max_display_mastering_luminance": 4000 } }))?;
+    let mut processor = Processor::new(config, Vec::new())?.with_tolerant(true);
+    processor.edit_file(&input_file)?;
+
+    let malformed = processor.malformed_nals();
+    assert_eq!(malformed.len(), 1);
+    assert_eq!(malformed[0].offset, expected_offset as u64);
+    assert_eq!(
+        data[expected_offset..expected_offset + 3],
+        [0x4E, 0x01, 0x89]
+    );
+
+    Ok(())
+}
+
+#[test]
+fn tolerant_malformed_mdcv_after_cll() -> Result<()> {
+    let temp = TempDir::new()?;
+
+    // The CLL NAL at offset 109 looks ahead to the malformed MDCV NAL at offset 122
+    let mut data = read("assets/regular.hevc")?;
+    assert_eq!(data[109..113], [0x4E, 0x01, 0x90, 0x04]);
+    data[125] = 0x30;
+    let input_file = write_input(&temp, "malformed.hevc", &data)?;
+
+    let config_file = write_config(
+        &temp,
+        "config.json",
+        &json!({
+            "cll": { "max_content_light_level": 2000, "transforms": ["clamp_to_mdcv"] }
+        }),
+    )?;
+    let output_file = temp.child("output.hevc");
+
+    edit_cmd(&input_file, &config_file, &output_file)
+        .arg("--tolerant")
+        .assert()
+        .success()
+        .stderr(predicate::str::contains(
+            "WARN  Passing through malformed SEI NAL offset=122 access_unit=0",
+        ))
+        .stderr(predicate::str::contains("malformed SEI NAL offset=109").not())
+        .stderr(predicate::str::contains(
+            "WARN  No MDCV metadata, the edits requiring it are skipped offset=109",
+        ))
+        .stdout(predicate::str::contains(
+            "Passed through 1 malformed SEI NAL units",
+        ));
+
+    // Only the first CLL is edited without a MDCV to clamp to
+    let output = read(&output_file)?;
+    assert_eq!(
+        count_matches(&output, &[0x4E, 0x01, 0x90, 0x04, 0x07, 0xD0, 0x01, 0x90]),
+        1
+    );
+
+    Ok(())
+}
+
+#[test]
+fn unparseable_untouched_metadata() -> Result<()> {
+    let temp = TempDir::new()?;
+
+    // MDCV SEI with a 2 bytes payload, after the last access unit
+    let short_mdcv: &[u8] = &[0, 0, 0, 1, 0x4E, 0x01, 0x89, 0x02, 0x12, 0x34, 0x80];
+    let mut data = read("assets/regular.hevc")?;
+    data.extend_from_slice(short_mdcv);
+    let input_file = write_input(&temp, "short_mdcv.hevc", &data)?;
+
+    // The MDCV isn't edited, and is left out of the provenance records
+    for config in [
+        json!({ "cll": { "max_content_light_level": 2000 } }),
+        json!({
+            "cll": { "max_content_light_level": 2000 },
+            "provenance": { "insert": true }
+        }),
+    ] {
+        let output = run_edit(&config, &input_file)?;
+        let expected = run_edit(&config, "assets/regular.hevc")?;
+
+        assert_same_output(&output, &[expected.as_slice(), short_mdcv].concat());
+    }
+
+    Ok(())
+}
+
+#[test]
+fn json_logs() -> Result<()> {
+    let temp = TempDir::new()?;
+
+    let input_file = Path::new("assets/multimsg-sei.hevc");
+    let config_file = Path::new("assets/example_config.json");
+    let output_file = temp.child("output.hevc");
+
+    let assert = edit_cmd(input_file, config_file, &output_file)
+        .arg("-vv")
+        .arg("--log-format")
+        .arg("json")
+        .assert()
+        .success();
+
+    let logs = std::str::from_utf8(&assert.get_output().stderr)?
+        .lines()
+        .map(serde_json::from_str)
+        .collect::<serde_json::Result<Vec<Value>>>()?;
+
+    assert!(logs.contains(&json!({
+        "level": "DEBUG",
+        "message": "Edited SEI message",
+        "offset": 2412,
+        "access_unit": 0,
+        "payload_type": 137,
+        "action": "edited"
+    })));
+    assert!(logs.contains(&json!({
+        "level": "DEBUG",
+        "message": "Split SEI NAL",
+        "offset": 2412,
+        "access_unit": 0,
+        "nals": 3
+    })));
+
+    // No logs, progress or summary
+    edit_cmd(input_file, config_file, &output_file)
+        .arg("-q")
+        .assert()
+        .success()
+        .stdout(predicate::str::is_empty())
+        .stderr(predicate::str::is_empty());
+
+    Ok(())
+}
+
+#[test]
+fn progress_json() -> Result<()> {
+    let temp = TempDir::new()?;
+    let output_file = temp.child("output.hevc");
+
+    let progress_events = |input_file: &str, config_file: &Path| -> Result<Vec<Value>> {
+        let assert = edit_cmd(input_file, config_file, &output_file)
+            .arg("--progress")
+            .arg("json")
+            .assert()
+            .success();
+
+        Ok(std::str::from_utf8(&assert.get_output().stderr)?
+            .lines()
+            .map(serde_json::from_str)
+            .collect::<serde_json::Result<Vec<Value>>>()?)
+    };
+
+    let events = progress_events(
+        "assets/regular.hevc",
+        Path::new("assets/example_config.json"),
+    )?;
+    let last = events.last().unwrap();
+
+    let total_bytes = std::fs::metadata("assets/regular.hevc")?.len();
+    assert_eq!(last["event"], "progress");
+    assert_eq!(last["phase"], "edit");
+    assert_eq!(last["done"], true);
+    assert_eq!(last["bytes"], total_bytes);
+    assert_eq!(last["total_bytes"], total_bytes);
+    assert_eq!(last["nals"], 795);
+    assert_eq!(last["edited_seis"], 4);
+    assert!(last["throughput"].as_f64().unwrap() > 0.0);
+
+    // The HEVC stream size isn't known for Matroska files
+    let events = progress_events(
+        "assets/regular.mkv",
+        Path::new("assets/example_config.json"),
+    )?;
+    let last = events.last().unwrap();
+
+    assert_eq!(last["done"], true);
+    assert!(last["bytes"].as_u64().unwrap() > 0);
+    assert_eq!(last["total_bytes"], Value::Null);
+    assert_eq!(last["nals"], 795);
+
+    // Scan phase before the edit
+    let config_file = write_config(
+        &temp,
+        "config.json",
+        &json!({ "cll": { "source": "hdr10plus" } }),
+    )?;
+    let events = progress_events("assets/multimsg-sei.hevc", &config_file)?;
+    let done: Vec<_> = events
+        .iter()
+        .filter(|event| event["done"] == true)
+        .collect();
+
+    let total_bytes = std::fs::metadata("assets/multimsg-sei.hevc")?.len();
+    assert_eq!(events[0]["phase"], "scan");
+    assert_eq!(done.len(), 2);
+    for (event, phase) in done.iter().zip(["scan", "edit"]) {
+        assert_eq!(event["phase"], phase);
+        assert_eq!(event["bytes"], total_bytes);
+        assert_eq!(event["total_bytes"], total_bytes);
+    }
+    assert_eq!(done[0]["edited_seis"], 0);
+
+    Ok(())
+}
+
+#[test]
+fn pipeline() -> Result<()> {
+    let temp = TempDir::new()?;
+    let config = example_config()?;
+
+    for input_file in ["assets/regular.hevc", "assets/regular.mkv"] {
+        assert_same_output(
+            &run_edit_with_args(&config, input_file, &["--pipeline"])?,
+            &run_edit(&config, input_file)?,
+        );
+    }
+
+    // Across several chunks and write buffers, with the state carried over between chunks
+    let regular = read("assets/regular.hevc")?;
+    let large_input = write_input(&temp, "large.hevc", &regular.repeat(60))?;
+    let config = json!({
+        "mdcv": { "max_display_mastering_luminance": 4000 },
+        "cll": { "transforms": ["clamp_to_mdcv"] },
+        "provenance": { "insert": true }
+    });
+    let serial = run_edit(&config, &large_input)?;
+    assert!(serial.len() > 1_000_000);
+    assert_same_output(
+        &run_edit_with_args(&config, &large_input, &["--pipeline"])?,
+        &serial,
+    );
+
+    // Piped input and output
+    edit_cmd("-", "assets/example_config.json", "-")
+        .arg("--pipeline")
+        .pipe_stdin("assets/regular.hevc")?
+        .assert()
+        .success()
+        .stdout(read("assets/regular_example_cfg.hevc")?);
+
+    // Editing errors stop the other stages, without any output
+    let failing_config = write_config(
+        &temp,
+        "failing.json",
+        &json!({
+            "sei_rules": [{ "payload_type": 137, "action": { "replace": { "hex": "00" } } }],
+            "mdcv": { "max_display_mastering_luminance": 4000 }
+        }),
+    )?;
+    let failed_output = temp.child("failed.hevc");
+
+    edit_cmd("assets/regular.hevc", &failing_config, &failed_output)
+        .arg("--pipeline")
+        .assert()
+        .failure();
+    failed_output.assert(predicate::path::missing());
+
+    Ok(())
+}